@@ -0,0 +1,120 @@
+use super::{Architecture, BundleError, Payload, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Name of the files, in gitignore syntax, listing what to leave out of
+/// an asset directory. They apply to their own directory and everything
+/// below it, and are never bundled themselves.
+pub const IGNORE_FILENAME: &str = ".bundleignore";
+
+fn default_payload() -> Payload {
+    Payload::Overlay
+}
+
+/// A directory of files to include in a bundle, optionally filtered by
+/// glob patterns matched against paths relative to the directory.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Asset {
+    pub path: PathBuf,
+    #[serde(default = "default_payload")]
+    pub payload: Payload,
+    /// Where the files go within the payload's directory.
+    #[serde(default)]
+    pub dest: PathBuf,
+    /// If given, only files matching one of these are included.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// If given, the asset is only included in this architecture's
+    /// variant of the bundle.
+    #[serde(default)]
+    pub architecture: Option<Architecture>,
+}
+
+impl Asset {
+    pub fn new<P: AsRef<Path>>(payload: Payload, path: P) -> Self {
+        Asset {
+            path: path.as_ref().to_path_buf(),
+            payload,
+            dest: PathBuf::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            architecture: None,
+        }
+    }
+
+    pub(crate) fn filter(&self) -> Result<AssetFilter> {
+        Ok(AssetFilter {
+            include: if self.include.is_empty() {
+                None
+            } else {
+                Some(glob_set(&self.include)?)
+            },
+            exclude: glob_set(&self.exclude)?,
+        })
+    }
+}
+
+pub(crate) struct AssetFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl AssetFilter {
+    pub(crate) fn matches(&self, relative: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative))
+            && !self.exclude.is_match(relative)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(Glob::new(pattern).map_err(|e| BundleError::InvalidGlob(e.to_string()))?);
+    }
+    set.build()
+        .map_err(|e| BundleError::InvalidGlob(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(include: &[&str], exclude: &[&str]) -> Asset {
+        Asset {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            ..Asset::new(Payload::Overlay, "assets")
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let everything = asset(&[], &[]).filter().unwrap();
+        assert!(everything.matches(Path::new("robot/main.py")));
+
+        let filter = asset(&["**/*.py", "*.toml"], &["**/test_*.py"])
+            .filter()
+            .unwrap();
+        assert!(filter.matches(Path::new("robot/main.py")));
+        assert!(filter.matches(Path::new("config.toml")));
+        assert!(!filter.matches(Path::new("robot/test_main.py")));
+        assert!(!filter.matches(Path::new("robot/main.pyc")));
+
+        let filter = asset(&[], &["**/*.pyc"]).filter().unwrap();
+        assert!(filter.matches(Path::new("robot/main.py")));
+        assert!(!filter.matches(Path::new("robot/main.pyc")));
+    }
+
+    #[test]
+    fn invalid_globs() {
+        for asset in [asset(&["[unclosed"], &[]), asset(&[], &["a/{b"])] {
+            assert!(matches!(asset.filter(), Err(BundleError::InvalidGlob(_))));
+        }
+    }
+}
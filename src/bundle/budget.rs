@@ -0,0 +1,247 @@
+use super::{ManifestEntry, Payload};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// How many of the largest entries an [`OverBudget`] error lists when
+/// displayed.
+const LARGEST_SHOWN: usize = 10;
+
+/// Size limits for a bundle.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeBudget {
+    /// Limit on the written archive, after compression.
+    pub archive: Option<u64>,
+    /// Limits on the uncompressed size of each kind of payload.
+    pub payloads: BTreeMap<Payload, u64>,
+}
+
+/// A limit which a bundle went over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exceeded {
+    /// The archive grew past `limit` while being written.
+    Archive { limit: u64 },
+    Payload {
+        payload: Payload,
+        size: u64,
+        limit: u64,
+    },
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exceeded::Archive { limit } => write!(f, "archive is over {} bytes", limit),
+            Exceeded::Payload {
+                payload,
+                size,
+                limit,
+            } => write!(
+                f,
+                "{} is {} bytes, over its {} byte budget",
+                payload.directory(),
+                size,
+                limit
+            ),
+        }
+    }
+}
+
+/// The limits a bundle exceeded, along with the size of every entry,
+/// largest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverBudget {
+    pub exceeded: Vec<Exceeded>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl OverBudget {
+    pub(crate) fn new(exceeded: Vec<Exceeded>, entries: &[ManifestEntry]) -> Self {
+        let mut entries = entries.to_vec();
+        entries.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        OverBudget { exceeded, entries }
+    }
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bundle is over its size budget:")?;
+        for exceeded in &self.exceeded {
+            write!(f, "\n  {}", exceeded)?;
+        }
+        write!(f, "\nlargest entries:")?;
+        for entry in self.entries.iter().take(LARGEST_SHOWN) {
+            write!(f, "\n  {:>12}  {}", entry.size, entry.path)?;
+        }
+        if self.entries.len() > LARGEST_SHOWN {
+            write!(f, "\n  ... and {} more", self.entries.len() - LARGEST_SHOWN)?;
+        }
+        Ok(())
+    }
+}
+
+impl SizeBudget {
+    /// Check the payload budgets against the sizes in a manifest.
+    pub(crate) fn check_payloads(&self, entries: &[ManifestEntry]) -> Vec<Exceeded> {
+        let mut totals: BTreeMap<Payload, u64> = BTreeMap::new();
+        for entry in entries {
+            if let Some(payload) = Payload::of_path(&entry.path) {
+                *totals.entry(payload).or_default() += entry.size;
+            }
+        }
+        self.payloads
+            .iter()
+            .filter_map(|(&payload, &limit)| {
+                let size = totals.get(&payload).copied().unwrap_or_default();
+                if size > limit {
+                    Some(Exceeded::Payload {
+                        payload,
+                        size,
+                        limit,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Fails writes once more than `limit` bytes have passed through it.
+pub(crate) struct LimitedWriter<W> {
+    inner: W,
+    written: u64,
+    limit: u64,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    /// Wrap `inner`, returning a flag which is set if the limit is hit.
+    pub(crate) fn new(inner: W, limit: u64) -> (Self, Rc<Cell<bool>>) {
+        let exceeded = Rc::new(Cell::new(false));
+        let writer = LimitedWriter {
+            inner,
+            written: 0,
+            limit,
+            exceeded: exceeded.clone(),
+        };
+        (writer, exceeded)
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > self.limit {
+            self.exceeded.set(true);
+            return Err(io::Error::other("bundle archive is over its size budget"));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, TempDir};
+    use crate::bundle::BundleError;
+    use sha2::{Digest, Sha256};
+
+    const FILES: &[(&str, &str)] = &[("main.py", "print(1)\n"), ("big.py", "# big\n")];
+
+    /// `len` bytes of hex hashes, which compress to no less than half
+    /// their size.
+    fn contents(len: usize) -> String {
+        let mut contents: String = (0..len / 64 + 1)
+            .map(|i| hex::encode(Sha256::digest(i.to_le_bytes())))
+            .collect();
+        contents.truncate(len);
+        contents
+    }
+
+    #[test]
+    fn within_budget() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.tar.gz");
+        builder(dir.path(), "[kit]\n", FILES)
+            .max_size(1024 * 1024)
+            .payload_budget(Payload::Usercode, 1024)
+            .payload_budget(Payload::Firmware, 0)
+            .build(&path)
+            .unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn payload_over_budget() {
+        let dir = TempDir::new();
+        let big = contents(2000);
+        let path = dir.join("bundle.tar.gz");
+        let result = builder(
+            dir.path(),
+            "[kit]\n",
+            &[("main.py", "print(1)\n"), ("big.py", &big)],
+        )
+        .payload_budget(Payload::Usercode, 1000)
+        .build(&path);
+        let over = match result {
+            Err(BundleError::OverBudget(over)) => over,
+            other => panic!("expected to be over budget, got {:?}", other),
+        };
+        assert_eq!(
+            over.exceeded,
+            [Exceeded::Payload {
+                payload: Payload::Usercode,
+                size: 2009,
+                limit: 1000,
+            }]
+        );
+        assert_eq!(over.entries[0].path, "usercode/big.py");
+        assert!(over
+            .to_string()
+            .contains("usercode is 2009 bytes, over its 1000 byte budget"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn archive_over_budget() {
+        let dir = TempDir::new();
+        let big = contents(20_000);
+        let path = dir.join("bundle.tar.gz");
+        let result = builder(dir.path(), "[kit]\n", &[("big.py", &big)])
+            .max_size(4096)
+            .build(&path);
+        match result {
+            Err(BundleError::OverBudget(over)) => {
+                assert_eq!(over.exceeded, [Exceeded::Archive { limit: 4096 }]);
+                assert_eq!(over.entries[0].path, "usercode/big.py");
+            }
+            other => panic!("expected to be over budget, got {:?}", other),
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lists_the_largest_entries() {
+        let entries: Vec<ManifestEntry> = (0..12)
+            .map(|i| ManifestEntry {
+                path: format!("usercode/{}.py", i),
+                size: i,
+                sha256: String::new(),
+            })
+            .collect();
+        let over = OverBudget::new(vec![Exceeded::Archive { limit: 1 }], &entries);
+        let text = over.to_string();
+        assert!(text.starts_with("bundle is over its size budget:\n  archive is over 1 bytes"));
+        assert!(text.contains("11  usercode/11.py"));
+        assert!(!text.contains("usercode/1.py"));
+        assert!(text.ends_with("... and 2 more"));
+    }
+}
@@ -0,0 +1,1006 @@
+use super::assets::{Asset, IGNORE_FILENAME};
+use super::budget::{Exceeded, LimitedWriter, OverBudget, SizeBudget};
+use super::checksums::{self, SUMS_SIGNATURE_FILENAME};
+use super::compression::{Codec, Compression, MemberWriter};
+use super::container::Container;
+use super::encryption::{self, Recipient, ENCRYPTED_SUFFIX};
+use super::hooks::{HookContext, HookStage, Hooks};
+use super::licenses::{self, License, LICENSES_FILENAME, LICENSES_PATH};
+use super::manifest::{hash_reader, HashingReader, HashingWriter};
+use super::provenance::{self, Provenance, PROVENANCE_SUFFIX};
+use super::sbom::{self, SbomInput, SbomPackage, SBOM_PATH};
+use super::signing::{ManifestSignature, SigningKey};
+use super::split::{self, SplitWriter};
+use super::steps::{BundleStep, Staging, StepContext, Steps};
+use super::store::BlobStore;
+use super::{
+    Architecture, BundleError, Manifest, ManifestEntry, Payload, Result, MANIFEST_PATH,
+    METADATA_PATH, SIGNATURE_PATH,
+};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{self, Component, Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+type OpenFn = Box<dyn Fn() -> io::Result<Box<dyn Read>> + Send + Sync>;
+
+/// Where a payload entry's contents come from.
+enum Source {
+    File(PathBuf),
+    Reader(OpenFn),
+    Bytes(Vec<u8>),
+}
+
+impl Source {
+    fn open(&self) -> io::Result<Box<dyn Read>> {
+        match self {
+            Source::File(path) => Ok(Box::new(File::open(path)?)),
+            Source::Reader(open) => open(),
+            Source::Bytes(data) => Ok(Box::new(io::Cursor::new(data.clone()))),
+        }
+    }
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => f.debug_tuple("File").field(path).finish(),
+            Source::Reader(_) => f.write_str("Reader"),
+            Source::Bytes(data) => f.debug_tuple("Bytes").field(&data.len()).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    source: Source,
+    path: String,
+}
+
+/// A file the builder writes itself, ahead of the payload.
+struct Generated {
+    path: &'static str,
+    data: Vec<u8>,
+}
+
+/// Everything that goes into one write of the archive.
+struct Plan<'a> {
+    /// The payload entries, including any staged by build steps.
+    payload: Vec<&'a Entry>,
+    generated: Vec<Generated>,
+    manifest: Manifest,
+}
+
+/// Packs a bundle TOML and its payload files into a bundle archive.
+///
+/// Payloads are never held in memory. Each is read twice when the archive
+/// is written: once to hash it for the manifest, which leads the archive,
+/// and once as it is streamed into the archive itself.
+#[derive(Debug)]
+pub struct BundleBuilder {
+    metadata: PathBuf,
+    entries: Vec<Entry>,
+    architecture: Option<Architecture>,
+    versions: BTreeMap<Payload, String>,
+    sbom: Option<Vec<SbomPackage>>,
+    licenses: Vec<License>,
+    signing_key: Option<SigningKey>,
+    compression: Compression,
+    container: Container,
+    recipients: Vec<Recipient>,
+    encrypt_archive: bool,
+    budget: SizeBudget,
+    cache: Option<BlobStore>,
+    part_size: Option<u64>,
+    checksums: bool,
+    provenance: Option<Provenance>,
+    hooks: Hooks,
+    steps: Steps,
+    #[cfg(feature = "openpgp")]
+    openpgp_cert: Option<super::openpgp::Cert>,
+}
+
+impl BundleBuilder {
+    pub fn new<P: AsRef<Path>>(metadata: P) -> Self {
+        BundleBuilder {
+            metadata: metadata.as_ref().to_path_buf(),
+            entries: Vec::new(),
+            architecture: None,
+            versions: BTreeMap::new(),
+            sbom: None,
+            licenses: Vec::new(),
+            signing_key: None,
+            compression: Compression::default(),
+            container: Container::default(),
+            recipients: Vec::new(),
+            encrypt_archive: false,
+            budget: SizeBudget::default(),
+            cache: None,
+            part_size: None,
+            checksums: false,
+            provenance: None,
+            hooks: Hooks::default(),
+            steps: Steps::default(),
+            #[cfg(feature = "openpgp")]
+            openpgp_cert: None,
+        }
+    }
+
+    /// Record `version` as the version of a payload's component in the
+    /// manifest.
+    pub fn version<V: Into<String>>(&mut self, payload: Payload, version: V) -> &mut Self {
+        self.versions.insert(payload, version.into());
+        self
+    }
+
+    /// Build the bundle as the variant for `architecture`, which is
+    /// recorded in the manifest and checked when it is installed. Set
+    /// this before adding assets, so those for other architectures are
+    /// left out.
+    pub fn architecture(&mut self, architecture: Architecture) -> &mut Self {
+        self.architecture = Some(architecture);
+        self
+    }
+
+    /// Generate an SPDX SBOM listing the bundle's components and firmware
+    /// and store it in the archive as `sbom.spdx`.
+    pub fn sbom(&mut self) -> &mut Self {
+        self.sbom.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// List a package the builder can't see for itself, such as kit
+    /// software or an OS package, in the SBOM. Implies
+    /// [`BundleBuilder::sbom`].
+    pub fn sbom_package(&mut self, package: SbomPackage) -> &mut Self {
+        self.sbom.get_or_insert_with(Vec::new).push(package);
+        self
+    }
+
+    /// Include `license` in the bundle's `LICENSES` document, along with
+    /// any declared in `.bundlelicenses` files in asset directories.
+    pub fn add_license(&mut self, license: License) -> &mut Self {
+        self.licenses.push(license);
+        self
+    }
+
+    /// Set the codec and level the archive is compressed with. Defaults to
+    /// gzip.
+    pub fn compression<C: Into<Compression>>(&mut self, compression: C) -> &mut Self {
+        self.compression = compression.into();
+        self
+    }
+
+    /// Set the archive format. Defaults to a tarball.
+    ///
+    /// Zip bundles are always deflated, at the level set by
+    /// [`BundleBuilder::compression`] if that is gzip, and can't be
+    /// encrypted as a whole or split.
+    pub fn container(&mut self, container: Container) -> &mut Self {
+        self.container = container;
+        self
+    }
+
+    /// Fail the build if the archive, once compressed, would be larger
+    /// than `bytes`.
+    pub fn max_size(&mut self, bytes: u64) -> &mut Self {
+        self.budget.archive = Some(bytes);
+        self
+    }
+
+    /// Fail the build if the files for `payload` total more than `bytes`,
+    /// before compression.
+    pub fn payload_budget(&mut self, payload: Payload, bytes: u64) -> &mut Self {
+        self.budget.payloads.insert(payload, bytes);
+        self
+    }
+
+    /// Reuse compressed payload entries from `store`, and add any it is
+    /// missing, so payloads which haven't changed since an earlier build
+    /// aren't compressed again.
+    ///
+    /// Each cached entry is compressed on its own rather than as part of
+    /// one stream, which costs a little in compression ratio. Encrypted
+    /// entries, and zip bundles, are never cached.
+    pub fn cache(&mut self, store: BlobStore) -> &mut Self {
+        self.cache = Some(store);
+        self
+    }
+
+    /// Have [`BundleBuilder::build`] split the archive into parts of at
+    /// most `part_size` bytes, such as [`split::FAT32_MAX_SIZE`].
+    pub fn split(&mut self, part_size: u64) -> &mut Self {
+        assert!(part_size > 0, "split archive parts can't be empty");
+        self.part_size = Some(part_size);
+        self
+    }
+
+    /// Have [`BundleBuilder::build`] add the archive, or its parts and
+    /// index if split, to a `SHA256SUMS` file next to it, which can be
+    /// checked with `sha256sum -c`.
+    ///
+    /// If the bundle is signed with OpenPGP, `SHA256SUMS.asc` is written
+    /// too, for `gpg --verify`.
+    pub fn checksums(&mut self) -> &mut Self {
+        self.checksums = true;
+        self
+    }
+
+    /// Write a signed provenance attestation for the bundle next to it
+    /// when it is built; see [`provenance`](super::provenance). The
+    /// bundle must be signed with [`BundleBuilder::sign`].
+    pub fn provenance(&mut self, provenance: Provenance) -> &mut Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Run `hook` at the start of [`BundleBuilder::build`], before
+    /// anything is read. Directories are scanned as they are added, so a
+    /// hook generating files should write them to paths added with
+    /// [`BundleBuilder::add_file`].
+    pub fn pre_build<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&HookContext) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.pre_build.push(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` once [`BundleBuilder::build`] has written and signed
+    /// the bundle. If it fails, the build fails, but the bundle is left
+    /// in place.
+    pub fn post_build<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&HookContext) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.post_build.push(Box::new(hook));
+        self
+    }
+
+    /// Register a custom build step. Steps run in the order they are
+    /// registered, each time the archive is written; see
+    /// [`steps`](super::steps).
+    pub fn step<S: BundleStep + 'static>(&mut self, step: S) -> &mut Self {
+        self.steps.0.push(Box::new(step));
+        self
+    }
+
+    /// Set the age recipients used by [`BundleBuilder::encrypt_archive`]
+    /// and [`BundleBuilder::add_encrypted_file`].
+    pub fn encrypt_to(&mut self, recipients: Vec<Recipient>) -> &mut Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Encrypt the whole archive to the recipients, once compressed.
+    pub fn encrypt_archive(&mut self) -> &mut Self {
+        self.encrypt_archive = true;
+        self
+    }
+
+    /// Sign the bundle's manifest with `key` when it is built.
+    pub fn sign(&mut self, key: SigningKey) -> &mut Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Also sign the manifest with an OpenPGP key. `cert` must hold
+    /// unencrypted secret key material.
+    #[cfg(feature = "openpgp")]
+    pub fn sign_openpgp(&mut self, cert: super::openpgp::Cert) -> &mut Self {
+        self.openpgp_cert = Some(cert);
+        self
+    }
+
+    /// Add a single file, stored at `dest` under the payload's directory.
+    pub fn add_file<S: AsRef<Path>, D: AsRef<Path>>(
+        &mut self,
+        payload: Payload,
+        source: S,
+        dest: D,
+    ) -> Result<&mut Self> {
+        let path = archive_path(payload, dest.as_ref())?;
+        self.push(Source::File(source.as_ref().to_path_buf()), path)?;
+        Ok(self)
+    }
+
+    /// Add a file encrypted to the recipients set by
+    /// [`BundleBuilder::encrypt_to`], stored at `dest` with an `.age`
+    /// suffix.
+    ///
+    /// The file is encrypted straight away and held in memory, so this is
+    /// meant for small secrets such as WiFi credentials.
+    pub fn add_encrypted_file<S: AsRef<Path>, D: AsRef<Path>>(
+        &mut self,
+        payload: Payload,
+        source: S,
+        dest: D,
+    ) -> Result<&mut Self> {
+        let mut path = archive_path(payload, dest.as_ref())?;
+        path.push_str(ENCRYPTED_SUFFIX);
+        let data = encryption::encrypt_reader(&self.recipients, File::open(source)?)?;
+        self.push(Source::Bytes(data), path)?;
+        Ok(self)
+    }
+
+    /// Add an entry whose contents come from a reader rather than a file,
+    /// such as a disk image being decompressed on the fly.
+    ///
+    /// `open` is called once per pass over the payload, and must produce
+    /// the same data each time.
+    pub fn add_reader<D, F, R>(&mut self, payload: Payload, dest: D, open: F) -> Result<&mut Self>
+    where
+        D: AsRef<Path>,
+        F: Fn() -> io::Result<R> + Send + Sync + 'static,
+        R: Read + 'static,
+    {
+        let path = archive_path(payload, dest.as_ref())?;
+        let open: OpenFn = Box::new(move || Ok(Box::new(open()?) as Box<dyn Read>));
+        self.push(Source::Reader(open), path)?;
+        Ok(self)
+    }
+
+    /// Add every file below `source`, keeping their paths relative to it.
+    ///
+    /// Files matched by a `.bundleignore` in `source` or any directory
+    /// below it are skipped.
+    pub fn add_dir<S: AsRef<Path>>(&mut self, payload: Payload, source: S) -> Result<&mut Self> {
+        self.add_asset(&Asset::new(payload, source))
+    }
+
+    /// Add the files in an asset directory which pass its filters and
+    /// aren't matched by a `.bundleignore`, and the licenses declared in
+    /// any `.bundlelicenses` files.
+    ///
+    /// Assets for another architecture than the bundle's are skipped.
+    pub fn add_asset(&mut self, asset: &Asset) -> Result<&mut Self> {
+        if asset.architecture.is_some() && asset.architecture != self.architecture {
+            return Ok(self);
+        }
+        let filter = asset.filter()?;
+        let walker = WalkBuilder::new(&asset.path)
+            .standard_filters(false)
+            .add_custom_ignore_filename(IGNORE_FILENAME)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            let entry = entry.map_err(|e| BundleError::Io(io::Error::other(e)))?;
+            if !entry.file_type().is_some_and(|kind| kind.is_file())
+                || entry.file_name() == IGNORE_FILENAME
+            {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(&asset.path)
+                .expect("walker yields paths below its root");
+            if entry.file_name() == LICENSES_FILENAME {
+                let dir = asset.dest.join(relative.parent().unwrap_or(Path::new("")));
+                let location = if dir.as_os_str().is_empty() {
+                    asset.payload.directory().to_string()
+                } else {
+                    archive_path(asset.payload, &dir)?
+                };
+                self.licenses
+                    .extend(licenses::read_declarations(entry.path(), &location)?);
+                continue;
+            }
+            if !filter.matches(relative) {
+                continue;
+            }
+            let path = archive_path(asset.payload, &asset.dest.join(relative))?;
+            self.push(Source::File(entry.path().to_path_buf()), path)?;
+        }
+        Ok(self)
+    }
+
+    fn push(&mut self, source: Source, path: String) -> Result<()> {
+        if self.entries.iter().any(|e| e.path == path) {
+            return Err(BundleError::DuplicateEntry(path));
+        }
+        self.entries.push(Entry { source, path });
+        Ok(())
+    }
+
+    fn read_metadata(&self) -> Result<String> {
+        let metadata = fs::read_to_string(&self.metadata)?;
+        metadata.parse::<toml::Table>()?;
+        Ok(metadata)
+    }
+
+    /// Run the build steps, returning the staging directory they ran in
+    /// and the entries they staged.
+    fn stage(&self) -> Result<(Option<Staging>, Vec<Entry>)> {
+        if self.steps.0.is_empty() {
+            return Ok((None, Vec::new()));
+        }
+        let staging = Staging::create()?;
+        let metadata = self.read_metadata()?.parse()?;
+        let files = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let source = match &entry.source {
+                    Source::File(path) => Some(path.as_path()),
+                    Source::Reader(_) | Source::Bytes(_) => None,
+                };
+                (entry.path.as_str(), source)
+            })
+            .collect();
+        self.steps.run(&StepContext {
+            staging: staging.path(),
+            metadata: &metadata,
+            files,
+        })?;
+
+        let mut staged = Vec::new();
+        for payload in Payload::ALL {
+            let root = staging.path().join(payload.directory());
+            if !root.is_dir() {
+                continue;
+            }
+            let walker = WalkBuilder::new(&root)
+                .standard_filters(false)
+                .sort_by_file_name(|a, b| a.cmp(b))
+                .build();
+            for entry in walker {
+                let entry = entry.map_err(|e| BundleError::Io(io::Error::other(e)))?;
+                if !entry.file_type().is_some_and(|kind| kind.is_file()) {
+                    continue;
+                }
+                let relative = entry
+                    .path()
+                    .strip_prefix(&root)
+                    .expect("walker yields paths below its root");
+                let path = archive_path(payload, relative)?;
+                if self.entries.iter().any(|e| e.path == path) {
+                    return Err(BundleError::DuplicateEntry(path));
+                }
+                staged.push(Entry {
+                    source: Source::File(entry.path().to_path_buf()),
+                    path,
+                });
+            }
+        }
+        Ok((Some(staging), staged))
+    }
+
+    fn hash_payload(entries: &[&Entry]) -> Result<Vec<ManifestEntry>> {
+        entries
+            .par_iter()
+            .map(|entry| {
+                let (size, sha256) = hash_reader(entry.source.open()?)?;
+                Ok(ManifestEntry {
+                    path: entry.path.clone(),
+                    size,
+                    sha256,
+                })
+            })
+            .collect()
+    }
+
+    /// The payloads which make up components of the bundle.
+    fn components(&self, payload: &[ManifestEntry]) -> Vec<Payload> {
+        Payload::ALL
+            .iter()
+            .copied()
+            .filter(|&kind| {
+                self.versions.contains_key(&kind)
+                    || payload
+                        .iter()
+                        .any(|entry| Payload::of_path(&entry.path) == Some(kind))
+            })
+            .collect()
+    }
+
+    /// The bundle TOML, then any other files the builder generates.
+    fn generate(&self, payload: &[ManifestEntry]) -> Result<Vec<Generated>> {
+        let metadata = self.read_metadata()?;
+        let mut generated = Vec::new();
+        if let Some(packages) = &self.sbom {
+            let sbom = sbom::generate(&SbomInput {
+                metadata: &metadata.parse()?,
+                payload,
+                components: &self.components(payload),
+                versions: &self.versions,
+                packages,
+            });
+            generated.push(Generated {
+                path: SBOM_PATH,
+                data: sbom.into_bytes(),
+            });
+        }
+        if !self.licenses.is_empty() {
+            generated.push(Generated {
+                path: LICENSES_PATH,
+                data: licenses::generate(&self.licenses).into_bytes(),
+            });
+        }
+        generated.insert(
+            0,
+            Generated {
+                path: METADATA_PATH,
+                data: metadata.into_bytes(),
+            },
+        );
+        Ok(generated)
+    }
+
+    /// Hash everything, generating any files which depend on the payload.
+    fn prepare<'a>(&'a self, staged: &'a [Entry]) -> Result<Plan<'a>> {
+        let sources: Vec<&Entry> = self.entries.iter().chain(staged).collect();
+        let payload = Self::hash_payload(&sources)?;
+        let generated = self.generate(&payload)?;
+        let mut entries = generated
+            .iter()
+            .map(|file| {
+                let (size, sha256) = hash_reader(file.data.as_slice())?;
+                Ok(ManifestEntry {
+                    path: file.path.to_string(),
+                    size,
+                    sha256,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let components = self.components(&payload);
+        entries.extend(payload);
+
+        let mut manifest = Manifest {
+            architecture: self.architecture,
+            entries,
+            ..Manifest::default()
+        };
+        manifest.components = components
+            .into_iter()
+            .map(|payload| super::Component {
+                payload,
+                version: self.versions.get(&payload).cloned(),
+                sha256: manifest.component_digest(payload),
+            })
+            .collect();
+        Ok(Plan {
+            payload: sources,
+            generated,
+            manifest,
+        })
+    }
+
+    /// Compute the manifest the bundle would have, without writing it.
+    /// This runs the build steps.
+    pub fn manifest(&self) -> Result<Manifest> {
+        let (_staging, staged) = self.stage()?;
+        Ok(self.prepare(&staged)?.manifest)
+    }
+
+    /// Write the bundle archive to `output`, which is removed again if the
+    /// build fails.
+    ///
+    /// If the archive is to be split, the parts are written next to
+    /// `output` and the index listing them to `output` itself.
+    pub fn build<P: AsRef<Path>>(&self, output: P) -> Result<Manifest> {
+        let output = output.as_ref();
+        if self.provenance.is_some() && self.signing_key.is_none() {
+            return Err(BundleError::UnsignedProvenance);
+        }
+        let mut context = HookContext {
+            stage: HookStage::PreBuild,
+            output: path::absolute(output)?,
+            metadata: path::absolute(&self.metadata)?,
+            manifest: None,
+        };
+        self.hooks.run(&context)?;
+        let manifest = match self.part_size {
+            Some(part_size) => self.build_split(output, part_size)?,
+            None => self.build_single(output)?,
+        };
+        context.stage = HookStage::PostBuild;
+        context.manifest = Some(manifest);
+        self.hooks.run(&context)?;
+        Ok(context.manifest.expect("manifest was just set"))
+    }
+
+    fn build_single(&self, output: &Path) -> Result<Manifest> {
+        let result = File::create(output)
+            .map_err(BundleError::from)
+            .and_then(|file| {
+                let mut file = HashingWriter::new(BufWriter::new(file));
+                let manifest = self.write_to(&mut file)?;
+                let (file, sha256) = file.finish();
+                file.into_inner()
+                    .map_err(|e| BundleError::Io(e.into_error()))?
+                    .sync_all()?;
+                self.write_sidecars(output, &manifest, vec![(file_name(output)?, sha256)])?;
+                Ok(manifest)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(output);
+            let _ = fs::remove_file(provenance_path(output));
+        }
+        result
+    }
+
+    fn build_split(&self, output: &Path, part_size: u64) -> Result<Manifest> {
+        if self.container == Container::Zip {
+            return Err(BundleError::UnsupportedZip("splitting".to_string()));
+        }
+        let mut writer = SplitWriter::new(output, part_size);
+        let result = self.write_to(&mut writer);
+        let parts = writer.part_paths();
+        let result = result.and_then(|manifest| {
+            let index = writer.finish()?;
+            if self.checksums || self.provenance.is_some() {
+                let (_, sha256) = hash_reader(File::open(output)?)?;
+                let mut files = vec![(file_name(output)?, sha256)];
+                files.extend(index.parts.into_iter().map(|part| (part.path, part.sha256)));
+                self.write_sidecars(output, &manifest, files)?;
+            }
+            Ok(manifest)
+        });
+        if result.is_err() {
+            split::remove(output, &parts);
+            let _ = fs::remove_file(provenance_path(output));
+        }
+        result
+    }
+
+    /// Write the files which go alongside the bundle, given the name and
+    /// SHA-256 of each file it was written to.
+    fn write_sidecars(
+        &self,
+        output: &Path,
+        manifest: &Manifest,
+        files: Vec<(String, String)>,
+    ) -> Result<()> {
+        if let (Some(provenance), Some(key)) = (&self.provenance, &self.signing_key) {
+            let statement = provenance::statement(provenance, manifest, &files);
+            fs::write(provenance_path(output), provenance::sign(&statement, key))?;
+        }
+        self.write_checksums(output, files)
+    }
+
+    /// Record `files` in the `SHA256SUMS` next to `output`, if asked to,
+    /// and sign it if the bundle is signed with OpenPGP.
+    fn write_checksums(&self, output: &Path, files: Vec<(String, String)>) -> Result<()> {
+        if !self.checksums {
+            return Ok(());
+        }
+        let dir = output.parent().unwrap_or_else(|| Path::new(""));
+        let contents = checksums::update(dir, &files)?;
+        let path = dir.join(SUMS_SIGNATURE_FILENAME);
+        match self.sign_checksums(&contents)? {
+            Some(signature) => fs::write(path, signature)?,
+            // An older signature no longer matches the updated sums.
+            None => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "openpgp")]
+    fn sign_checksums(&self, contents: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.openpgp_cert
+            .as_ref()
+            .map(|cert| super::openpgp::sign_detached(cert, contents))
+            .transpose()
+    }
+
+    #[cfg(not(feature = "openpgp"))]
+    fn sign_checksums(&self, _contents: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Write the bundle archive to an arbitrary writer, unsplit.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<Manifest> {
+        if self.container == Container::Zip {
+            if self.encrypt_archive {
+                return Err(BundleError::UnsupportedZip(
+                    "encrypting the whole archive".to_string(),
+                ));
+            }
+            if self.compression.codec() != Codec::Gzip {
+                return Err(BundleError::UnsupportedZip(format!(
+                    "{:?} compression",
+                    self.compression.codec()
+                )));
+            }
+        }
+        let (_staging, staged) = self.stage()?;
+        let plan = self.prepare(&staged)?;
+        let manifest = &plan.manifest;
+
+        let exceeded = self.budget.check_payloads(&manifest.entries);
+        if !exceeded.is_empty() {
+            return Err(BundleError::OverBudget(OverBudget::new(
+                exceeded,
+                &manifest.entries,
+            )));
+        }
+
+        match self.budget.archive {
+            Some(limit) => {
+                let (writer, hit_limit) = LimitedWriter::new(writer, limit);
+                match self.write_maybe_encrypted(writer, &plan) {
+                    Err(_) if hit_limit.get() => {
+                        return Err(BundleError::OverBudget(OverBudget::new(
+                            vec![Exceeded::Archive { limit }],
+                            &manifest.entries,
+                        )));
+                    }
+                    result => {
+                        result?;
+                    }
+                }
+            }
+            None => {
+                self.write_maybe_encrypted(writer, &plan)?;
+            }
+        }
+        Ok(plan.manifest)
+    }
+
+    fn write_maybe_encrypted<W: Write>(&self, writer: W, plan: &Plan) -> Result<W> {
+        if self.encrypt_archive {
+            let writer = encryption::encrypt_writer(&self.recipients, writer)?;
+            Ok(self.write_container(writer, plan)?.finish()?)
+        } else {
+            self.write_container(writer, plan)
+        }
+    }
+
+    fn write_container<W: Write>(&self, writer: W, plan: &Plan) -> Result<W> {
+        match self.container {
+            Container::Tar => self.write_archive(writer, plan),
+            Container::Zip => self.write_zip(writer, plan),
+        }
+    }
+
+    /// The entries which lead the archive, before the payload.
+    ///
+    /// The bundle TOML comes first and the manifest and its signatures
+    /// straight after, so readers can find them quickly.
+    fn head_entries(&self, plan: &Plan) -> Result<Vec<(&str, Vec<u8>)>> {
+        let generated = &plan.generated;
+        let manifest_bytes = plan.manifest.to_string().into_bytes();
+        let mut entries = vec![(generated[0].path, generated[0].data.clone())];
+        if let Some(key) = &self.signing_key {
+            let signature = ManifestSignature::sign(key, &manifest_bytes);
+            entries.push((SIGNATURE_PATH, signature.to_bytes()));
+        }
+        #[cfg(feature = "openpgp")]
+        if let Some(cert) = &self.openpgp_cert {
+            let signature = super::openpgp::sign_detached(cert, &manifest_bytes)?;
+            entries.push((super::OPENPGP_SIGNATURE_PATH, signature));
+        }
+        entries.insert(1, (MANIFEST_PATH, manifest_bytes));
+        entries.extend(
+            generated[1..]
+                .iter()
+                .map(|file| (file.path, file.data.clone())),
+        );
+        Ok(entries)
+    }
+
+    fn write_archive<W: Write>(&self, writer: W, plan: &Plan) -> Result<W> {
+        let mut archive = tar::Builder::new(MemberWriter::new(self.compression, writer));
+        for (path, data) in self.head_entries(plan)? {
+            append_bytes(&mut archive, path, &data)?;
+        }
+
+        // The manifest lists the generated files first, then the rest line
+        // up with the payload entries.
+        for (&entry, expected) in plan
+            .payload
+            .iter()
+            .zip(&plan.manifest.entries[plan.generated.len()..])
+        {
+            let mut header = entry_header(entry, expected.size)?;
+            match (&self.cache, &entry.source) {
+                (Some(store), Source::File(_) | Source::Reader(_)) => {
+                    self.append_cached(&mut archive, store, &mut header, entry, expected)?
+                }
+                _ => append_entry(&mut archive, &mut header, entry, expected)?,
+            }
+        }
+
+        Ok(archive.into_inner()?.finish()?)
+    }
+
+    fn write_zip<W: Write>(&self, writer: W, plan: &Plan) -> Result<W> {
+        let mut zip = ZipWriter::new_stream(writer);
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(Some(i64::from(self.compression.level())))
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(0o644);
+        for (path, data) in self.head_entries(plan)? {
+            zip.start_file(path, options)?;
+            zip.write_all(&data)?;
+        }
+
+        for (&entry, expected) in plan
+            .payload
+            .iter()
+            .zip(&plan.manifest.entries[plan.generated.len()..])
+        {
+            let header = entry_header(entry, expected.size)?;
+            let options = options
+                .unix_permissions(header.mode()?)
+                .large_file(expected.size >= u64::from(u32::MAX));
+            zip.start_file(entry.path.as_str(), options)?;
+            let mut reader = HashingReader::new(entry.source.open()?.take(expected.size));
+            io::copy(&mut reader, &mut zip)?;
+            if reader.finish() != (expected.size, expected.sha256.clone()) {
+                return Err(BundleError::ChangedDuringBuild(entry.path.clone()));
+            }
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Copy an entry's compressed bytes from the store if they are there,
+    /// otherwise compress it as a member of its own and store that.
+    fn append_cached<W: Write>(
+        &self,
+        archive: &mut tar::Builder<MemberWriter<W>>,
+        store: &BlobStore,
+        header: &mut tar::Header,
+        entry: &Entry,
+        expected: &ManifestEntry,
+    ) -> Result<()> {
+        let key = self.blob_key(header, expected);
+        if let Some(mut blob) = store.get(&key)? {
+            archive.get_mut().write_raw(&mut blob)?;
+            return Ok(());
+        }
+
+        let (temp, file) = store.create_temp()?;
+        let result = archive
+            .get_mut()
+            .start_member(Some(file))
+            .map_err(BundleError::from)
+            .and_then(|()| append_entry(archive, header, entry, expected))
+            .and_then(|()| Ok(archive.get_mut().end_member()?))
+            .and_then(|_| Ok(store.commit(&temp, &key)?));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// The store key for an entry: a hash of everything that goes into
+    /// its compressed bytes.
+    fn blob_key(&self, header: &tar::Header, expected: &ManifestEntry) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.compression.codec().extension());
+        hasher.update(self.compression.level().to_be_bytes());
+        hasher.update(header.as_bytes());
+        hasher.update(expected.path.as_bytes());
+        hasher.update([0]);
+        hasher.update(expected.sha256.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// The tar header for an entry, also used for its zip permissions.
+fn entry_header(entry: &Entry, size: u64) -> Result<tar::Header> {
+    let mut header = tar::Header::new_gnu();
+    match &entry.source {
+        Source::File(path) => {
+            header.set_metadata_in_mode(&fs::metadata(path)?, tar::HeaderMode::Deterministic)
+        }
+        Source::Reader(_) | Source::Bytes(_) => header.set_mode(0o644),
+    }
+    header.set_size(size);
+    Ok(header)
+}
+
+fn append_entry<W: Write>(
+    archive: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    entry: &Entry,
+    expected: &ManifestEntry,
+) -> Result<()> {
+    let mut reader = HashingReader::new(entry.source.open()?.take(expected.size));
+    archive.append_data(header, &entry.path, &mut reader)?;
+    if reader.finish() != (expected.size, expected.sha256.clone()) {
+        return Err(BundleError::ChangedDuringBuild(entry.path.clone()));
+    }
+    Ok(())
+}
+
+pub(crate) fn append_bytes<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    archive.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+fn provenance_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(PROVENANCE_SUFFIX);
+    PathBuf::from(path)
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| BundleError::InvalidPath(path.to_path_buf()))
+}
+
+/// Build the in-archive path for `dest`, rejecting anything that could
+/// point outside the payload directory.
+fn archive_path(payload: Payload, dest: &Path) -> Result<String> {
+    let mut path = String::from(payload.directory());
+    for component in dest.components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part
+                    .to_str()
+                    .ok_or_else(|| BundleError::InvalidPath(dest.to_path_buf()))?;
+                path.push('/');
+                path.push_str(part);
+            }
+            Component::CurDir => {}
+            _ => return Err(BundleError::InvalidPath(dest.to_path_buf())),
+        }
+    }
+    if path.len() == payload.directory().len() {
+        return Err(BundleError::InvalidPath(dest.to_path_buf()));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+
+    #[test]
+    fn nested_ignore_files_exclude_entries() {
+        let dir = TempDir::new();
+        fs::write(dir.join("bundle.toml"), "[kit]\n").unwrap();
+        let overlay = dir.join("overlay");
+        fs::create_dir_all(overlay.join("robot/logs")).unwrap();
+        for path in [
+            "notes.txt",
+            "robot/main.py",
+            "robot/main.pyc",
+            "robot/notes.txt",
+            "robot/logs/run.log",
+        ] {
+            fs::write(overlay.join(path), path).unwrap();
+        }
+        fs::write(overlay.join(IGNORE_FILENAME), "*.pyc\n").unwrap();
+        fs::write(
+            overlay.join("robot").join(IGNORE_FILENAME),
+            "notes.txt\nlogs/\n",
+        )
+        .unwrap();
+
+        let mut builder = BundleBuilder::new(dir.join("bundle.toml"));
+        builder.add_dir(Payload::Overlay, &overlay).unwrap();
+        let manifest = builder.build(dir.join("bundle.tar.gz")).unwrap();
+        let paths: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|e| e.path.as_str())
+            .filter(|path| path.starts_with("overlay/"))
+            .collect();
+        // The nested file only applies below its own directory.
+        assert_eq!(paths, ["overlay/notes.txt", "overlay/robot/main.py"]);
+    }
+}
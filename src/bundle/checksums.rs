@@ -0,0 +1,51 @@
+use super::{BundleError, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the coreutils-style checksum file written next to bundles.
+pub const SUMS_FILENAME: &str = "SHA256SUMS";
+
+/// Name of the detached OpenPGP signature over [`SUMS_FILENAME`].
+pub const SUMS_SIGNATURE_FILENAME: &str = "SHA256SUMS.asc";
+
+/// Add or replace the lines for `files`, given as file name and hex
+/// SHA-256, in the `SHA256SUMS` in `dir`, returning its new contents.
+///
+/// Lines for other files are kept, so one `SHA256SUMS` can cover every
+/// bundle built into a directory, as `sha256sum -c` expects.
+pub(crate) fn update(dir: &Path, files: &[(String, String)]) -> Result<Vec<u8>> {
+    for (name, _) in files {
+        // sha256sum escapes these, and other tools disagree about how.
+        if name.contains(['\n', '\r', '\\']) {
+            return Err(BundleError::InvalidPath(PathBuf::from(name)));
+        }
+    }
+    let path = dir.join(SUMS_FILENAME);
+    let existing = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut contents = String::new();
+    for line in existing.lines() {
+        let replaced = line_name(line).is_some_and(|name| files.iter().any(|(n, _)| n == name));
+        if !replaced {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+    }
+    for (name, sha256) in files {
+        contents.push_str(&format!("{}  {}\n", sha256, name));
+    }
+    fs::write(&path, &contents)?;
+    Ok(contents.into_bytes())
+}
+
+/// The file name a `sha256sum` line is for, in either text or binary
+/// mode.
+fn line_name(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once(' ')?;
+    Some(rest.strip_prefix([' ', '*']).unwrap_or(rest))
+}
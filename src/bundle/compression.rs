@@ -0,0 +1,389 @@
+use super::{BundleError, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use liblzma::read::XzDecoder;
+use liblzma::write::XzEncoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+
+/// The compression formats bundle archives can be written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    pub fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            Codec::Gzip => 0..=9,
+            Codec::Zstd => 1..=22,
+            Codec::Xz => 0..=9,
+        }
+    }
+
+    pub fn default_level(self) -> u32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+        }
+    }
+
+    /// The conventional file extension for a tarball using this codec.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "tar.gz",
+            Codec::Zstd => "tar.zst",
+            Codec::Xz => "tar.xz",
+        }
+    }
+
+    fn from_magic(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// A codec and the level to compress with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    codec: Codec,
+    level: u32,
+}
+
+impl Compression {
+    pub fn new(codec: Codec, level: u32) -> Result<Self> {
+        if !codec.levels().contains(&level) {
+            return Err(BundleError::InvalidCompressionLevel(codec, level));
+        }
+        Ok(Compression { codec, level })
+    }
+
+    pub fn codec(self) -> Codec {
+        self.codec
+    }
+
+    pub fn level(self) -> u32 {
+        self.level
+    }
+
+    pub(crate) fn encoder<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self.codec {
+            Codec::Gzip => {
+                Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::new(self.level)))
+            }
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, self.level as i32)?),
+            Codec::Xz => Encoder::Xz(XzEncoder::new(writer, self.level)),
+        })
+    }
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        Compression {
+            codec,
+            level: codec.default_level(),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Codec::Gzip.into()
+    }
+}
+
+pub(crate) enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A compressed stream written as a series of independent members (gzip
+/// members, zstd frames or xz streams), which decoders read back as one
+/// continuous stream.
+///
+/// Writing while no member is open starts one, so a stream written with
+/// no calls to [`MemberWriter::start_member`] is a single member.
+pub(crate) struct MemberWriter<W: Write> {
+    compression: Compression,
+    state: Option<MemberState<W>>,
+}
+
+enum MemberState<W: Write> {
+    Idle(W),
+    Active(Encoder<Tee<W>>),
+}
+
+/// Passes compressed bytes through, keeping a copy if asked to.
+struct Tee<W> {
+    inner: W,
+    copy: Option<BufWriter<File>>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.write_all(&buf[..written])?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(copy) = &mut self.copy {
+            copy.flush()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> MemberWriter<W> {
+    pub(crate) fn new(compression: Compression, writer: W) -> Self {
+        MemberWriter {
+            compression,
+            state: Some(MemberState::Idle(writer)),
+        }
+    }
+
+    fn take_state(&mut self) -> io::Result<MemberState<W>> {
+        self.state
+            .take()
+            .ok_or_else(|| io::Error::other("compressed stream failed earlier"))
+    }
+
+    /// Finish the open member, if any, and return the underlying writer.
+    fn idle(&mut self) -> io::Result<&mut W> {
+        self.end_member()?;
+        match &mut self.state {
+            Some(MemberState::Idle(writer)) => Ok(writer),
+            _ => Err(io::Error::other("compressed stream failed earlier")),
+        }
+    }
+
+    /// Start a new member, finishing the open one. If `copy` is given,
+    /// the member's compressed bytes are also written to it.
+    pub(crate) fn start_member(&mut self, copy: Option<File>) -> io::Result<()> {
+        self.end_member()?;
+        let inner = match self.take_state()? {
+            MemberState::Idle(writer) => writer,
+            MemberState::Active(_) => unreachable!("member was just ended"),
+        };
+        let tee = Tee {
+            inner,
+            copy: copy.map(BufWriter::new),
+        };
+        self.state = Some(MemberState::Active(self.compression.encoder(tee)?));
+        Ok(())
+    }
+
+    /// Finish the open member, returning the file its bytes were copied
+    /// to, if any.
+    pub(crate) fn end_member(&mut self) -> io::Result<Option<File>> {
+        match self.take_state()? {
+            MemberState::Idle(writer) => {
+                self.state = Some(MemberState::Idle(writer));
+                Ok(None)
+            }
+            MemberState::Active(encoder) => {
+                let tee = encoder.finish()?;
+                self.state = Some(MemberState::Idle(tee.inner));
+                tee.copy
+                    .map(|copy| copy.into_inner().map_err(|e| e.into_error()))
+                    .transpose()
+            }
+        }
+    }
+
+    /// Copy already compressed members from `reader` straight into the
+    /// stream.
+    pub(crate) fn write_raw<R: Read>(&mut self, reader: &mut R) -> io::Result<u64> {
+        io::copy(reader, self.idle()?)
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.end_member()?;
+        match self.take_state()? {
+            MemberState::Idle(writer) => Ok(writer),
+            MemberState::Active(_) => unreachable!("member was just ended"),
+        }
+    }
+}
+
+impl<W: Write> Write for MemberWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(MemberState::Idle(_)) = self.state {
+            self.start_member(None)?;
+        }
+        match &mut self.state {
+            Some(MemberState::Active(encoder)) => encoder.write(buf),
+            _ => Err(io::Error::other("compressed stream failed earlier")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            Some(MemberState::Active(encoder)) => encoder.flush(),
+            Some(MemberState::Idle(writer)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Work out which codec a compressed archive uses from its first bytes.
+pub(crate) fn detect<R: Read>(reader: R) -> Result<Codec> {
+    sniff(&mut BufReader::new(reader))
+}
+
+fn sniff<R: Read>(reader: &mut BufReader<R>) -> Result<Codec> {
+    Codec::from_magic(reader.fill_buf()?).ok_or(BundleError::UnknownCompression)
+}
+
+/// Open a compressed archive, working out the codec from its first bytes.
+///
+/// Archives may be made of several concatenated members; see
+/// [`MemberWriter`].
+pub(crate) fn decoder<R: Read + 'static>(reader: R) -> Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    let codec = sniff(&mut reader)?;
+    Ok(match codec {
+        Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Codec::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, TempDir};
+    use crate::bundle::{Bundle, Verification};
+
+    const CODECS: [Codec; 3] = [Codec::Gzip, Codec::Zstd, Codec::Xz];
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut encoder = compression.encoder(Vec::new()).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(data: Vec<u8>) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        decoder(io::Cursor::new(data))
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = b"robot bundle ".repeat(100);
+        for codec in CODECS {
+            for level in [*codec.levels().start(), *codec.levels().end()] {
+                let compressed = compress(Compression::new(codec, level).unwrap(), &data);
+                assert_eq!(detect(compressed.as_slice()).unwrap(), codec);
+                assert_eq!(decompress(compressed), data, "{:?} level {}", codec, level);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_several_members() {
+        for codec in CODECS {
+            let mut writer = MemberWriter::new(codec.into(), Vec::new());
+            writer.write_all(b"first ").unwrap();
+            writer.start_member(None).unwrap();
+            writer.write_all(b"second").unwrap();
+            assert_eq!(decompress(writer.finish().unwrap()), b"first second");
+        }
+    }
+
+    #[test]
+    fn builds_bundles_with_each_codec() {
+        let dir = TempDir::new();
+        for codec in CODECS {
+            let path = dir.join(format!("bundle.{}", codec.extension()));
+            builder(dir.path(), "[kit]\n", &[("main.py", "print(1)\n")])
+                .compression(codec)
+                .build(&path)
+                .unwrap();
+            let bundle = Bundle::open(&path).unwrap();
+            assert_eq!(bundle.codec().unwrap(), codec);
+            assert_eq!(bundle.verify(&[]).unwrap(), Verification::Unsigned);
+        }
+    }
+
+    #[test]
+    fn unknown_compression() {
+        for data in [&b"plain tarball"[..], b"", b"\x1f"] {
+            assert!(matches!(detect(data), Err(BundleError::UnknownCompression)));
+            assert!(matches!(
+                decoder(io::Cursor::new(data.to_vec())),
+                Err(BundleError::UnknownCompression)
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_levels() {
+        for (codec, level) in [
+            (Codec::Gzip, 10),
+            (Codec::Zstd, 0),
+            (Codec::Zstd, 23),
+            (Codec::Xz, 10),
+        ] {
+            assert!(matches!(
+                Compression::new(codec, level),
+                Err(BundleError::InvalidCompressionLevel(c, l)) if c == codec && l == level
+            ));
+        }
+        let compression = Compression::new(Codec::Zstd, 19).unwrap();
+        assert_eq!(
+            (compression.codec(), compression.level()),
+            (Codec::Zstd, 19)
+        );
+        assert_eq!(
+            Compression::default(),
+            Compression::new(Codec::Gzip, 6).unwrap()
+        );
+    }
+}
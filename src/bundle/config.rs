@@ -0,0 +1,264 @@
+use super::hooks::{self, HookCommands, HookContext, HookStage};
+use super::sbom::SbomPackage;
+use super::{Architecture, Asset, BundleBuilder, BundleError, Manifest, Payload, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{self, Path, PathBuf};
+
+/// A bundle build described in TOML, as an alternative to driving
+/// [`BundleBuilder`] directly:
+///
+/// ```toml
+/// metadata = "bundle.toml"
+/// architectures = ["armhf", "arm64"]
+///
+/// [versions]
+/// firmware = "2.1.0"
+///
+/// [[assets]]
+/// path = "overlay/"
+/// exclude = ["**/*.pyc"]
+///
+/// [[assets]]
+/// path = "firmware/"
+/// payload = "firmware"
+///
+/// [[assets]]
+/// path = "drivers/arm64/"
+/// dest = "drivers"
+/// architecture = "arm64"
+///
+/// # Generate an SBOM, listing these packages as well as the payload.
+/// [[sbom.packages]]
+/// name = "robot-kit"
+/// version = "2024.1.0"
+///
+/// [hooks]
+/// pre_build = [["python3", "scripts/calibrate.py"]]
+/// ```
+///
+/// Relative paths are resolved against the directory holding the file,
+/// which is also where hooks are run.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildConfig {
+    pub metadata: PathBuf,
+    /// The architecture variants to build with [`BuildConfig::build_all`].
+    #[serde(default)]
+    pub architectures: Vec<Architecture>,
+    /// Component versions to record in the manifest.
+    #[serde(default)]
+    pub versions: BTreeMap<Payload, String>,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+    #[serde(default)]
+    pub sbom: Option<SbomConfig>,
+    /// See [`hooks::run_command`].
+    #[serde(default)]
+    pub hooks: HookCommands,
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+/// The `[sbom]` table, whose presence turns SBOM generation on.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SbomConfig {
+    #[serde(default)]
+    pub packages: Vec<SbomPackage>,
+}
+
+impl BuildConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config: BuildConfig =
+            toml::from_str(&fs::read_to_string(path)?).map_err(BundleError::Config)?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        config.base = base.to_path_buf();
+        config.metadata = base.join(&config.metadata);
+        for asset in &mut config.assets {
+            asset.path = base.join(&asset.path);
+        }
+        Ok(config)
+    }
+
+    /// Run the pre-build hooks, then build the bundle to `output`, leaving
+    /// out assets for particular architectures.
+    pub fn build<P: AsRef<Path>>(&self, output: P) -> Result<Manifest> {
+        self.build_with(None, output.as_ref(), &|_| {})
+    }
+
+    /// As [`BuildConfig::build`], for the variant for `architecture`.
+    pub fn build_variant<P: AsRef<Path>>(
+        &self,
+        architecture: Architecture,
+        output: P,
+    ) -> Result<Manifest> {
+        self.build_with(Some(architecture), output.as_ref(), &|_| {})
+    }
+
+    /// Build every variant listed in `architectures`, each to
+    /// [`Architecture::variant_path`] of `output`, or just the one bundle
+    /// to `output` if none are listed. Returns where each was written.
+    pub fn build_all<P: AsRef<Path>>(&self, output: P) -> Result<Vec<(PathBuf, Manifest)>> {
+        self.build_all_with(output, |_| {})
+    }
+
+    /// As [`BuildConfig::build_all`], letting `customise` set up each
+    /// builder further before it builds, such as to sign the bundle.
+    pub fn build_all_with<P, F>(&self, output: P, customise: F) -> Result<Vec<(PathBuf, Manifest)>>
+    where
+        P: AsRef<Path>,
+        F: Fn(&mut BundleBuilder),
+    {
+        let output = output.as_ref();
+        if self.architectures.is_empty() {
+            let manifest = self.build_with(None, output, &customise)?;
+            return Ok(vec![(output.to_path_buf(), manifest)]);
+        }
+        self.architectures
+            .iter()
+            .map(|&architecture| {
+                let path = architecture.variant_path(output);
+                let manifest = self.build_with(Some(architecture), &path, &customise)?;
+                Ok((path, manifest))
+            })
+            .collect()
+    }
+
+    fn build_with(
+        &self,
+        architecture: Option<Architecture>,
+        output: &Path,
+        customise: &dyn Fn(&mut BundleBuilder),
+    ) -> Result<Manifest> {
+        let context = HookContext {
+            stage: HookStage::PreBuild,
+            output: path::absolute(output)?,
+            metadata: path::absolute(&self.metadata)?,
+            manifest: None,
+        };
+        for command in &self.hooks.pre_build {
+            hooks::run_command(command, &self.base, &context)?;
+        }
+        let mut builder = self.builder_for(architecture)?;
+        customise(&mut builder);
+        builder.build(output)
+    }
+
+    /// A builder for the bundle, with the post-build hooks set.
+    ///
+    /// The asset directories are scanned straight away, so this doesn't
+    /// run the pre-build hooks, which may generate files in them; use
+    /// [`BuildConfig::build`] for that.
+    pub fn builder(&self) -> Result<BundleBuilder> {
+        self.builder_for(None)
+    }
+
+    /// As [`BuildConfig::builder`], for the variant for `architecture`.
+    pub fn variant_builder(&self, architecture: Architecture) -> Result<BundleBuilder> {
+        self.builder_for(Some(architecture))
+    }
+
+    fn builder_for(&self, architecture: Option<Architecture>) -> Result<BundleBuilder> {
+        let mut builder = BundleBuilder::new(&self.metadata);
+        if let Some(architecture) = architecture {
+            builder.architecture(architecture);
+        }
+        for (&payload, version) in &self.versions {
+            builder.version(payload, version.clone());
+        }
+        for asset in &self.assets {
+            builder.add_asset(asset)?;
+        }
+        for command in &self.hooks.post_build {
+            let command = command.clone();
+            let base = self.base.clone();
+            builder.post_build(move |context| hooks::run_command(&command, &base, context));
+        }
+        if let Some(sbom) = &self.sbom {
+            builder.sbom();
+            for package in &sbom.packages {
+                builder.sbom_package(package.clone());
+            }
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+
+    fn load(dir: &TempDir, text: &str) -> Result<BuildConfig> {
+        let path = dir.join("build.toml");
+        fs::write(&path, text).unwrap();
+        BuildConfig::load(path)
+    }
+
+    #[test]
+    fn resolves_paths_against_its_directory() {
+        let dir = TempDir::new();
+        let config = load(
+            &dir,
+            "metadata = \"bundle.toml\"\n\
+             \n\
+             [[assets]]\n\
+             path = \"overlay/\"\n\
+             exclude = [\"**/*.pyc\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.metadata, dir.join("bundle.toml"));
+        assert_eq!(config.assets.len(), 1);
+        assert_eq!(config.assets[0].path, dir.join("overlay/"));
+        assert_eq!(config.assets[0].payload, Payload::Overlay);
+        assert_eq!(config.assets[0].exclude, ["**/*.pyc"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let dir = TempDir::new();
+        for text in [
+            "metadata = ",
+            "[[assets]]\npath = \"overlay/\"\n",
+            "metadata = \"bundle.toml\"\nunknown = 1\n",
+            "metadata = \"bundle.toml\"\n[[assets]]\npath = \"overlay/\"\npayload = \"nowhere\"\n",
+        ] {
+            assert!(
+                matches!(load(&dir, text), Err(BundleError::Config(_))),
+                "{:?} should not parse",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn builds_filtered_assets() {
+        let dir = TempDir::new();
+        fs::write(dir.join("bundle.toml"), "[kit]\n").unwrap();
+        fs::create_dir_all(dir.join("overlay/robot")).unwrap();
+        for name in ["main.py", "main.pyc", "notes.txt"] {
+            fs::write(dir.join("overlay/robot").join(name), name).unwrap();
+        }
+        let config = load(
+            &dir,
+            "metadata = \"bundle.toml\"\n\
+             \n\
+             [[assets]]\n\
+             path = \"overlay\"\n\
+             include = [\"**/*.py*\"]\n\
+             exclude = [\"**/*.pyc\"]\n",
+        )
+        .unwrap();
+        let manifest = config.build(dir.join("bundle.tar.gz")).unwrap();
+        let paths: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|e| e.path.as_str())
+            .filter(|path| path.starts_with("overlay/"))
+            .collect();
+        assert_eq!(paths, ["overlay/robot/main.py"]);
+    }
+}
@@ -0,0 +1,237 @@
+use super::{BundleError, Result};
+use flate2::read::DeflateDecoder;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+
+/// The archive formats bundles can be packed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Container {
+    /// A compressed tarball, which is what robots install from.
+    #[default]
+    Tar,
+    /// A zip file, which can be opened by Explorer on Windows to look
+    /// through a bundle before flashing it. Entries are always deflated.
+    Zip,
+}
+
+impl Container {
+    /// The file extension for bundles using this container, given the
+    /// codec a tarball would be compressed with.
+    pub fn extension(self, codec: super::Codec) -> &'static str {
+        match self {
+            Container::Tar => codec.extension(),
+            Container::Zip => "zip",
+        }
+    }
+
+    pub(crate) fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(b"PK\x03\x04") {
+            Container::Zip
+        } else {
+            Container::Tar
+        }
+    }
+}
+
+/// Where one zip entry's data is, so it can be read without holding a
+/// borrow of the [`ZipArchive`].
+struct ZipEntry {
+    header: Vec<u8>,
+    start: u64,
+    compressed_size: u64,
+    size: u64,
+    deflated: bool,
+}
+
+/// Read the zip bundle at `path` as an uncompressed tarball, so it can be
+/// handled by everything that reads bundles.
+///
+/// The tarball has no end of archive marker, which tar readers take the
+/// end of the stream in place of.
+pub(crate) fn zip_as_tar(path: &Path) -> Result<Box<dyn Read>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        let deflated = match file.compression() {
+            CompressionMethod::Stored => false,
+            CompressionMethod::Deflated => true,
+            method => return Err(BundleError::UnsupportedZip(method.to_string())),
+        };
+        let mut header = tar::Header::new_gnu();
+        if file.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+        } else {
+            header.set_size(file.size());
+        }
+        header.set_mode(file.unix_mode().map_or(0o644, |mode| mode & 0o7777));
+        entries.push(ZipEntry {
+            header: tar_header(&mut header, &file.name()?)?,
+            start: file
+                .data_start()
+                .expect("raw zip entries know their data start"),
+            compressed_size: file.compressed_size(),
+            size: header.size()?,
+            deflated,
+        });
+    }
+    Ok(Box::new(ZipAsTar {
+        file: archive.into_inner(),
+        entries: entries.into_iter(),
+        current: Box::new(io::empty()),
+    }))
+}
+
+/// The tar header blocks for an entry, including any GNU long name
+/// blocks needed for its path.
+fn tar_header(header: &mut tar::Header, path: &str) -> io::Result<Vec<u8>> {
+    // The tar builder knows how to write long names, but also writes the
+    // end of archive marker when it is finished with, which isn't wanted.
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(header, path, io::empty())?;
+    let mut blocks = builder.into_inner()?;
+    blocks.truncate(blocks.len() - 1024);
+    Ok(blocks)
+}
+
+struct ZipAsTar {
+    file: File,
+    entries: std::vec::IntoIter<ZipEntry>,
+    current: Box<dyn Read>,
+}
+
+impl Read for ZipAsTar {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let entry = match self.entries.next() {
+                Some(entry) => entry,
+                None => return Ok(0),
+            };
+            let mut file = self.file.try_clone()?;
+            file.seek(SeekFrom::Start(entry.start))?;
+            let data = file.take(entry.compressed_size);
+            let data: Box<dyn Read> = if entry.deflated {
+                Box::new(DeflateDecoder::new(data))
+            } else {
+                Box::new(data)
+            };
+            let padding = (512 - entry.size % 512) % 512;
+            self.current = Box::new(
+                Cursor::new(entry.header)
+                    .chain(data.take(entry.size))
+                    .chain(io::repeat(0).take(padding)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, TempDir};
+    use crate::bundle::{Bundle, Codec, Verification};
+    use std::fs;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    const METADATA: &str = "[kit]\nname = \"kit\"\n";
+
+    #[test]
+    fn zip_bundles_verify_and_extract() {
+        let dir = TempDir::new();
+        // Long enough to need GNU long name blocks once read as a tarball.
+        let long = format!("{}/main.py", "deep".repeat(30));
+        let path = dir.join("bundle.zip");
+        builder(
+            dir.path(),
+            METADATA,
+            &[("main.py", "print(1)\n"), (&long, "print(2)\n")],
+        )
+        .container(Container::Zip)
+        .build(&path)
+        .unwrap();
+
+        let bundle = Bundle::open(&path).unwrap();
+        assert_eq!(bundle.container().unwrap(), Container::Zip);
+        assert_eq!(bundle.codec().unwrap(), Codec::Gzip);
+        assert_eq!(bundle.verify(&[]).unwrap(), Verification::Unsigned);
+
+        let extracted = dir.join("extracted");
+        bundle.extract_to(&extracted).unwrap();
+        assert_eq!(
+            fs::read_to_string(extracted.join("usercode/main.py")).unwrap(),
+            "print(1)\n"
+        );
+        assert_eq!(
+            fs::read_to_string(extracted.join("usercode").join(&long)).unwrap(),
+            "print(2)\n"
+        );
+    }
+
+    #[test]
+    fn refuses_other_compression_methods() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("bundle.toml", options).unwrap();
+        zip.write_all(METADATA.as_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        // Mark the entry as bzip2 compressed, in both its local and
+        // central directory headers.
+        let mut data = fs::read(&path).unwrap();
+        for (signature, offset) in [(b"PK\x03\x04", 8), (b"PK\x01\x02", 10)] {
+            let start = data
+                .windows(4)
+                .position(|window| window == signature)
+                .unwrap();
+            data[start + offset..start + offset + 2].copy_from_slice(&12u16.to_le_bytes());
+        }
+        fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            zip_as_tar(&path),
+            Err(BundleError::UnsupportedZip(_))
+        ));
+        assert!(matches!(
+            Bundle::peek(&path),
+            Err(BundleError::UnsupportedZip(_))
+        ));
+    }
+
+    #[test]
+    fn zip_bundles_cannot_be_split_or_encrypted() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.zip");
+        let build = |change: &dyn Fn(&mut crate::bundle::BundleBuilder)| {
+            let mut builder = builder(dir.path(), METADATA, &[]);
+            builder.container(Container::Zip);
+            change(&mut builder);
+            builder.build(&path)
+        };
+        let recipient = crate::bundle::encryption::Identity::generate().to_public();
+        for result in [
+            build(&|builder| {
+                builder.split(1024);
+            }),
+            build(&|builder| {
+                builder
+                    .encrypt_to(vec![recipient.clone()])
+                    .encrypt_archive();
+            }),
+            build(&|builder| {
+                builder.compression(Codec::Zstd);
+            }),
+        ] {
+            assert!(matches!(result, Err(BundleError::UnsupportedZip(_))));
+        }
+    }
+}
@@ -0,0 +1,561 @@
+//! Delta bundles: just the entries which changed between two bundles,
+//! applied on top of an extracted copy of the older one.
+//!
+//! A delta carries the newer bundle's manifest and signatures, so once
+//! applied the directory holds exactly what extracting the newer bundle
+//! would have produced. The signature is checked before anything is
+//! applied, as is every file against the signed manifest.
+
+use super::builder::append_bytes;
+use super::compression::{self, Compression};
+use super::extract::{check_no_symlinks, extract_archive, safe_relative_path};
+use super::manifest::hash_reader;
+use super::reader::{parse_manifest, read_head_entry, Head};
+use super::signing::{ManifestSignature, VerifyingKey};
+use super::{
+    Bundle, BundleError, ExtractLimits, ExtractReport, ExtractedFile, Manifest, Payload, Result,
+    MANIFEST_PATH, OPENPGP_SIGNATURE_PATH, SIGNATURE_PATH,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Path of the delta description inside a delta archive.
+pub const DELTA_PATH: &str = "delta.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeltaInfo {
+    /// SHA-256 of the manifest the delta applies on top of.
+    base: String,
+    /// What the delta removes, for information only, as this file isn't
+    /// signed. Removals are worked out from the manifests when applying.
+    removed: Vec<String>,
+}
+
+/// What a delta changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeltaSummary {
+    /// Entries which are new or whose contents changed.
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Components which are new, changed or no longer present.
+    pub components: Vec<Payload>,
+}
+
+impl Bundle {
+    /// Write a delta to `output` which turns an extracted `base` into this
+    /// bundle.
+    pub fn delta_from<P: AsRef<Path>>(&self, base: &Bundle, output: P) -> Result<DeltaSummary> {
+        // The delta would hold this bundle's contents unencrypted.
+        if self.is_encrypted()? {
+            return Err(BundleError::EncryptedDelta);
+        }
+        let base_head = base.head()?;
+        let base_manifest = parse_manifest(&base_head.manifest)?;
+        let head = self.head()?;
+        let manifest = parse_manifest(&head.manifest)?;
+
+        let mut summary = DeltaSummary::default();
+        for entry in &manifest.entries {
+            if base_manifest.get(&entry.path) != Some(entry) {
+                summary.changed.push(entry.path.clone());
+            }
+        }
+        for entry in &base_manifest.entries {
+            if manifest.get(&entry.path).is_none() {
+                summary.removed.push(entry.path.clone());
+            }
+        }
+
+        for payload in Payload::ALL {
+            if manifest.component(payload) != base_manifest.component(payload) {
+                summary.components.push(payload);
+            }
+        }
+
+        let mut removed = summary.removed.clone();
+        if base_head.signature.is_some() && head.signature.is_none() {
+            removed.push(SIGNATURE_PATH.to_string());
+        }
+        if base_head.openpgp_signature.is_some() && head.openpgp_signature.is_none() {
+            removed.push(OPENPGP_SIGNATURE_PATH.to_string());
+        }
+        let info = DeltaInfo {
+            base: hash_reader(base_head.manifest.as_slice())?.1,
+            removed,
+        };
+
+        let output = output.as_ref();
+        let result = self.write_delta(output, &info, &head, &summary.changed);
+        if result.is_err() {
+            let _ = fs::remove_file(output);
+        }
+        result.map(|()| summary)
+    }
+
+    fn write_delta(
+        &self,
+        output: &Path,
+        info: &DeltaInfo,
+        head: &Head,
+        changed: &[String],
+    ) -> Result<()> {
+        // Deltas use the same codec as the bundle they lead to.
+        let compression = Compression::from(self.codec()?);
+        let file = File::create(output)?;
+        let mut archive = tar::Builder::new(compression.encoder(file)?);
+        let info = toml::to_string(info).expect("delta info always serializes");
+        append_bytes(&mut archive, DELTA_PATH, info.as_bytes())?;
+        append_bytes(&mut archive, MANIFEST_PATH, &head.manifest)?;
+        if let Some(signature) = &head.signature {
+            append_bytes(&mut archive, SIGNATURE_PATH, signature)?;
+        }
+        if let Some(signature) = &head.openpgp_signature {
+            append_bytes(&mut archive, OPENPGP_SIGNATURE_PATH, signature)?;
+        }
+
+        let changed: HashSet<&str> = changed.iter().map(String::as_str).collect();
+        let mut source = self.archive()?;
+        for entry in source.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            if entry.header().entry_type().is_file() && changed.contains(path.as_str()) {
+                let header = entry.header().clone();
+                archive.append(&header, entry)?;
+            }
+        }
+
+        archive.into_inner()?.finish()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Directory inside the install directory a delta is unpacked into and
+/// checked before any of it is moved into place.
+const STAGING_DIR: &str = ".delta-staging";
+
+/// Apply the delta at `delta` on top of the bundle extracted in `dir`,
+/// provided its manifest is signed by one of `trusted`.
+///
+/// The delta must have been made against the bundle currently in `dir`.
+/// It is unpacked alongside, and its signature and every file it holds
+/// checked before anything in `dir` changes. What to remove is worked out
+/// from the installed and new manifests. The new manifest goes into place
+/// last, so if applying is interrupted the old one is still there and the
+/// delta can be applied again.
+pub fn apply_delta<D: AsRef<Path>, P: AsRef<Path>>(
+    delta: D,
+    dir: P,
+    trusted: &[VerifyingKey],
+) -> Result<ExtractReport> {
+    apply_delta_with(delta.as_ref(), dir.as_ref(), |staging, manifest| {
+        let signature = read_staged(staging, SIGNATURE_PATH)?;
+        ManifestSignature::from_bytes(&signature)?.verify(manifest, trusted)
+    })
+}
+
+/// As [`apply_delta`], but checking the OpenPGP signature instead.
+#[cfg(feature = "openpgp")]
+pub fn apply_delta_openpgp<D: AsRef<Path>, P: AsRef<Path>>(
+    delta: D,
+    dir: P,
+    trusted: &[super::openpgp::Cert],
+) -> Result<ExtractReport> {
+    apply_delta_with(delta.as_ref(), dir.as_ref(), |staging, manifest| {
+        let signature = read_staged(staging, OPENPGP_SIGNATURE_PATH)?;
+        super::openpgp::verify_detached(&signature, manifest, trusted).map(|_| ())
+    })
+}
+
+fn apply_delta_with<F>(delta: &Path, dir: &Path, check_signature: F) -> Result<ExtractReport>
+where
+    F: FnOnce(&Path, &[u8]) -> Result<()>,
+{
+    let info = read_info(delta)?;
+
+    let installed = fs::read(dir.join(MANIFEST_PATH))?;
+    if hash_reader(installed.as_slice())?.1 != info.base {
+        return Err(BundleError::DeltaBaseMismatch);
+    }
+    let base = parse_manifest(&installed)?;
+
+    let staging = dir.join(STAGING_DIR);
+    match fs::remove_dir_all(&staging) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let result = stage(delta, &staging, &base, check_signature)
+        .and_then(|(manifest, report)| install(&staging, dir, &base, &manifest, report));
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// A signature file from the staged delta, which must be there.
+fn read_staged(staging: &Path, path: &str) -> Result<Vec<u8>> {
+    match fs::read(staging.join(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(BundleError::Unsigned),
+        result => Ok(result?),
+    }
+}
+
+/// Unpack the delta into `staging`, check the new manifest's signature,
+/// and check the delta holds exactly the files which changed from `base`,
+/// as the new manifest records them.
+fn stage<F>(
+    delta: &Path,
+    staging: &Path,
+    base: &Manifest,
+    check_signature: F,
+) -> Result<(Manifest, ExtractReport)>
+where
+    F: FnOnce(&Path, &[u8]) -> Result<()>,
+{
+    let mut archive = tar::Archive::new(compression::decoder(File::open(delta)?)?);
+    let report = extract_archive(
+        &mut archive,
+        staging,
+        ExtractLimits::default(),
+        &[DELTA_PATH],
+    )?;
+
+    let manifest_bytes = match fs::read(staging.join(MANIFEST_PATH)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(BundleError::MissingEntry(MANIFEST_PATH))
+        }
+        result => result?,
+    };
+    check_signature(staging, &manifest_bytes)?;
+    let manifest = parse_manifest(&manifest_bytes)?;
+
+    let mut staged = HashSet::new();
+    for file in &report.files {
+        let path = file.path.to_string_lossy().replace('\\', "/");
+        if file.path.starts_with(STAGING_DIR) {
+            return Err(BundleError::UnsafeEntry {
+                path,
+                reason: "path is reserved for applying deltas",
+            });
+        }
+        if let MANIFEST_PATH | SIGNATURE_PATH | OPENPGP_SIGNATURE_PATH = path.as_str() {
+            continue;
+        }
+        let expected = manifest
+            .get(&path)
+            .ok_or_else(|| BundleError::DeltaMismatch(path.clone()))?;
+        if hash_reader(File::open(staging.join(&file.path))?)?
+            != (expected.size, expected.sha256.clone())
+        {
+            return Err(BundleError::DeltaMismatch(path));
+        }
+        staged.insert(path);
+    }
+    // Anything changed but left out would be stale once applied.
+    for entry in &manifest.entries {
+        if base.get(&entry.path) != Some(entry) && !staged.contains(&entry.path) {
+            return Err(BundleError::DeltaMismatch(entry.path.clone()));
+        }
+    }
+    Ok((manifest, report))
+}
+
+/// Move the checked files from `staging` into `dir` and remove what is in
+/// `base` but not `manifest`, leaving the manifest and its signatures
+/// until last.
+fn install(
+    staging: &Path,
+    dir: &Path,
+    base: &Manifest,
+    manifest: &Manifest,
+    report: ExtractReport,
+) -> Result<ExtractReport> {
+    let is_head = |file: &&ExtractedFile| {
+        matches!(
+            file.path.to_str(),
+            Some(MANIFEST_PATH | SIGNATURE_PATH | OPENPGP_SIGNATURE_PATH)
+        )
+    };
+    let mut moved = HashSet::new();
+    let payload = report.files.iter().filter(|file| !is_head(file));
+    for file in payload {
+        if moved.insert(&file.path) {
+            move_into_place(staging, dir, &file.path)?;
+        }
+    }
+
+    let removed = base
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .filter(|path| manifest.get(path).is_none());
+    for path in removed {
+        remove(dir, path)?;
+    }
+    // Signatures the new bundle doesn't have would only fail to verify.
+    for path in [SIGNATURE_PATH, OPENPGP_SIGNATURE_PATH] {
+        if !staging.join(path).exists() {
+            remove(dir, path)?;
+        }
+    }
+
+    // The manifest comes last, as it marks the delta as applied.
+    let mut head: Vec<&ExtractedFile> = report.files.iter().filter(is_head).collect();
+    head.sort_by_key(|file| file.path.to_str() == Some(MANIFEST_PATH));
+    for file in head {
+        if moved.insert(&file.path) {
+            move_into_place(staging, dir, &file.path)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Remove `path` from `dir` if it is there, along with any directories
+/// that leaves empty.
+fn remove(dir: &Path, path: &str) -> Result<()> {
+    let relative = safe_relative_path(path)?;
+    check_no_symlinks(dir, &relative, path)?;
+    match fs::remove_file(dir.join(&relative)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    // Failing just means the directory still has something in it.
+    for parent in relative.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || fs::remove_dir(dir.join(parent)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn move_into_place(staging: &Path, dir: &Path, relative: &Path) -> Result<()> {
+    check_no_symlinks(dir, relative, &relative.to_string_lossy())?;
+    let target = dir.join(relative);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(staging.join(relative), target)?;
+    Ok(())
+}
+
+fn read_info(delta: &Path) -> Result<DeltaInfo> {
+    let mut archive = tar::Archive::new(compression::decoder(File::open(delta)?)?);
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or(BundleError::MissingEntry(DELTA_PATH))??;
+    if entry.path()?.to_str() != Some(DELTA_PATH) {
+        return Err(BundleError::MissingEntry(DELTA_PATH));
+    }
+    let text = String::from_utf8(read_head_entry(&mut entry, DELTA_PATH)?)
+        .map_err(|_| BundleError::NotUtf8(DELTA_PATH))?;
+    toml::from_str(&text).map_err(BundleError::Delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::signing::SigningKey;
+    use crate::bundle::testing::{builder, rewrite_tarball, TempDir};
+    use std::path::PathBuf;
+
+    const METADATA: &str = "[kit]\nname = \"kit\"\n";
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn trusted() -> Vec<VerifyingKey> {
+        vec![key(1).verifying_key()]
+    }
+
+    /// Build `files` as a bundle at `dir/name`, signed by `signer` if
+    /// given.
+    fn bundle(
+        dir: &TempDir,
+        name: &str,
+        signer: Option<&SigningKey>,
+        files: &[(&str, &str)],
+    ) -> Bundle {
+        let sources = dir.join(format!("{}-sources", name));
+        fs::create_dir_all(&sources).unwrap();
+        let path = dir.join(name);
+        let mut builder = builder(&sources, METADATA, files);
+        if let Some(signer) = signer {
+            builder.sign(signer.clone());
+        }
+        builder.build(&path).unwrap();
+        Bundle::open(&path).unwrap()
+    }
+
+    const OLD: &[(&str, &str)] = &[
+        ("main.py", "print(1)\n"),
+        ("keep.py", "kept\n"),
+        ("old.py", "gone\n"),
+    ];
+    const NEW: &[(&str, &str)] = &[
+        ("main.py", "print(2)\n"),
+        ("keep.py", "kept\n"),
+        ("lib/new.py", "new\n"),
+    ];
+
+    /// Extract `old`, and make a delta from it to `new`, signed by
+    /// `signer` if given.
+    fn setup_signed(dir: &TempDir, signer: Option<&SigningKey>) -> (PathBuf, PathBuf, Bundle) {
+        let old = bundle(dir, "old.tar.gz", Some(&key(1)), OLD);
+        let new = bundle(dir, "new.tar.gz", signer, NEW);
+        let install = dir.join("install");
+        old.extract_to(&install).unwrap();
+        let delta = dir.join("delta.tar.gz");
+        new.delta_from(&old, &delta).unwrap();
+        (install, delta, new)
+    }
+
+    fn setup(dir: &TempDir) -> (PathBuf, PathBuf, Bundle) {
+        setup_signed(dir, Some(&key(1)))
+    }
+
+    fn read(dir: &Path, path: &str) -> Option<String> {
+        fs::read_to_string(dir.join(path)).ok()
+    }
+
+    /// Check nothing in `install` has changed from the old bundle.
+    fn assert_untouched(install: &Path, manifest: &Option<String>) {
+        assert_eq!(&read(install, MANIFEST_PATH), manifest);
+        assert_eq!(read(install, "usercode/main.py").unwrap(), "print(1)\n");
+        assert_eq!(read(install, "usercode/keep.py").unwrap(), "kept\n");
+        assert_eq!(read(install, "usercode/old.py").unwrap(), "gone\n");
+        assert_eq!(read(install, "usercode/lib/new.py"), None);
+        assert!(!install.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn applies_delta() {
+        let dir = TempDir::new();
+        let (install, delta, new) = setup(&dir);
+        apply_delta(&delta, &install, &trusted()).unwrap();
+
+        let expected = dir.join("expected");
+        new.extract_to(&expected).unwrap();
+        for path in [
+            MANIFEST_PATH,
+            SIGNATURE_PATH,
+            "usercode/main.py",
+            "usercode/keep.py",
+            "usercode/lib/new.py",
+        ] {
+            assert_eq!(read(&install, path), read(&expected, path), "{}", path);
+        }
+        assert_eq!(read(&install, "usercode/old.py"), None);
+        assert!(!install.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn refuses_wrong_base() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup(&dir);
+        apply_delta(&delta, &install, &trusted()).unwrap();
+        assert!(matches!(
+            apply_delta(&delta, &install, &trusted()),
+            Err(BundleError::DeltaBaseMismatch)
+        ));
+    }
+
+    #[test]
+    fn tampered_entry_leaves_install_alone() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup(&dir);
+        let manifest = read(&install, MANIFEST_PATH);
+
+        let tampered = dir.join("tampered.tar.gz");
+        rewrite_tarball(&delta, &tampered, |path, data| {
+            if path == "usercode/main.py" {
+                *data = b"print(3)\n".to_vec();
+            }
+        });
+
+        assert!(matches!(
+            apply_delta(&tampered, &install, &trusted()),
+            Err(BundleError::DeltaMismatch(path)) if path == "usercode/main.py"
+        ));
+        assert_untouched(&install, &manifest);
+
+        // The untampered delta still applies.
+        apply_delta(&delta, &install, &trusted()).unwrap();
+        assert_eq!(read(&install, "usercode/main.py").unwrap(), "print(2)\n");
+    }
+
+    #[test]
+    fn refuses_forged_delta() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup_signed(&dir, Some(&key(2)));
+        let manifest = read(&install, MANIFEST_PATH);
+        assert!(matches!(
+            apply_delta(&delta, &install, &trusted()),
+            Err(BundleError::UntrustedKey(_))
+        ));
+        assert_untouched(&install, &manifest);
+    }
+
+    #[test]
+    fn refuses_forged_manifest() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup(&dir);
+        let manifest = read(&install, MANIFEST_PATH);
+
+        // Ship different code, with a manifest to match.
+        let (size, sha256) = hash_reader(&b"print(3)\n"[..]).unwrap();
+        let (old_size, old_sha256) = hash_reader(&b"print(2)\n"[..]).unwrap();
+        let forged = dir.join("forged.tar.gz");
+        rewrite_tarball(&delta, &forged, |path, data| match path {
+            "usercode/main.py" => *data = b"print(3)\n".to_vec(),
+            MANIFEST_PATH => {
+                let text = String::from_utf8(data.clone()).unwrap();
+                *data = text
+                    .replace(&old_sha256, &sha256)
+                    .replace(&format!("size = {}", old_size), &format!("size = {}", size))
+                    .into_bytes();
+            }
+            _ => {}
+        });
+
+        assert!(matches!(
+            apply_delta(&forged, &install, &trusted()),
+            Err(BundleError::BadSignature)
+        ));
+        assert_untouched(&install, &manifest);
+    }
+
+    #[test]
+    fn refuses_unsigned_delta() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup_signed(&dir, None);
+        let manifest = read(&install, MANIFEST_PATH);
+        assert!(matches!(
+            apply_delta(&delta, &install, &trusted()),
+            Err(BundleError::Unsigned)
+        ));
+        assert_untouched(&install, &manifest);
+    }
+
+    #[test]
+    fn removals_come_from_the_manifests() {
+        let dir = TempDir::new();
+        let (install, delta, _) = setup(&dir);
+
+        let forged = dir.join("forged.tar.gz");
+        rewrite_tarball(&delta, &forged, |path, data| {
+            if path == DELTA_PATH {
+                let mut info: DeltaInfo =
+                    toml::from_str(std::str::from_utf8(data).unwrap()).unwrap();
+                info.removed = vec!["usercode/keep.py".to_string(), "bundle.toml".to_string()];
+                *data = toml::to_string(&info).unwrap().into_bytes();
+            }
+        });
+        apply_delta(&forged, &install, &trusted()).unwrap();
+        assert_eq!(read(&install, "usercode/keep.py").unwrap(), "kept\n");
+        assert!(install.join("bundle.toml").exists());
+        assert_eq!(read(&install, "usercode/old.py"), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! In-place edits of bundle TOML files.
+//!
+//! [`MetadataFile`] changes fields by their dotted paths, such as
+//! `wifi.psk`, leaving the rest of the file as it was, comments and
+//! layout included, so the same change can be scripted across many
+//! files without them drifting apart.
+
+use super::{BundleError, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+
+/// A bundle TOML file, loaded for editing.
+#[derive(Debug, Clone)]
+pub struct MetadataFile {
+    path: PathBuf,
+    document: DocumentMut,
+}
+
+impl MetadataFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        Ok(MetadataFile {
+            path: path.to_path_buf(),
+            document: fs::read_to_string(path)?.parse()?,
+        })
+    }
+
+    /// The value at `field`, if it is set and isn't a table.
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        let keys = field_keys(field)?;
+        let (last, parents) = keys.split_last()?;
+        let mut table: &dyn TableLike = self.document.as_table();
+        for key in parents {
+            table = table.get(key)?.as_table_like()?;
+        }
+        table.get(last)?.as_value()
+    }
+
+    /// Set the field at `field` from `value` as it would be typed on the
+    /// command line.
+    ///
+    /// A field which is already set keeps its type, so `value` must be a
+    /// valid integer to replace an integer, while a string is replaced
+    /// with `value` as it is, without any quotes. For new fields, `value`
+    /// is taken as TOML if it is valid TOML, such as `3`, `true` or
+    /// `[1, 2]`, and as a string otherwise. Tables along the way are
+    /// created as needed.
+    pub fn set(&mut self, field: &str, value: &str) -> Result<()> {
+        let invalid = |reason: String| BundleError::Field {
+            field: field.to_string(),
+            reason,
+        };
+        let keys = field_keys(field).ok_or_else(|| invalid("not a dotted path".to_string()))?;
+        let (last, parents) = keys.split_last().expect("paths have a key");
+
+        let mut table: &mut dyn TableLike = self.document.as_table_mut();
+        for (depth, key) in parents.iter().enumerate() {
+            table = table
+                .entry(key)
+                .or_insert_with(toml_edit::table)
+                .as_table_like_mut()
+                .ok_or_else(|| invalid(format!("{} is not a table", keys[..=depth].join("."))))?;
+        }
+        match table.get_mut(last) {
+            Some(Item::Value(existing)) => {
+                let mut replacement = typed_value(existing, value).map_err(invalid)?;
+                *replacement.decor_mut() = existing.decor().clone();
+                *existing = replacement;
+            }
+            Some(Item::None) | None => {
+                let value = value.parse().unwrap_or_else(|_| Value::from(value));
+                table.insert(last, Item::Value(value));
+            }
+            Some(_) => return Err(invalid("is a table".to_string())),
+        }
+        Ok(())
+    }
+
+    /// Write the file back where it was loaded from, keeping its
+    /// permissions, since bundle TOML often holds credentials.
+    pub fn save(&self) -> Result<()> {
+        self.stage()?.commit()
+    }
+
+    /// Write the file to a temporary file beside it, to be moved into
+    /// place by [`StagedFile::commit`], so several files can be written
+    /// out before any of them is replaced.
+    pub fn stage(&self) -> Result<StagedFile> {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = self.path.with_file_name(format!(".{}.tmp", name));
+        let permissions = fs::metadata(&self.path)?.permissions();
+        // Never follow or reuse anything already at the temporary path.
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let message = format!("{} already exists", temp.display());
+                return Err(io::Error::new(e.kind(), message).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let staged = StagedFile {
+            temp,
+            path: self.path.clone(),
+            committed: false,
+        };
+        // Narrow the permissions before writing anything secret.
+        file.set_permissions(permissions)?;
+        file.write_all(self.document.to_string().as_bytes())?;
+        file.sync_all()?;
+        Ok(staged)
+    }
+}
+
+/// An edited file written out beside the original, which is removed
+/// unless it is committed.
+#[derive(Debug)]
+pub struct StagedFile {
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// Replace the original file with the edited one.
+    pub fn commit(mut self) -> Result<()> {
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// The keys of a dotted path, if none of them are empty.
+fn field_keys(field: &str) -> Option<Vec<&str>> {
+    let keys: Vec<&str> = field.split('.').collect();
+    if keys.iter().any(|key| key.is_empty()) {
+        return None;
+    }
+    Some(keys)
+}
+
+/// Parse `value` as the same type as `existing`.
+fn typed_value(existing: &Value, value: &str) -> std::result::Result<Value, String> {
+    let expected = || format!("expected {}, not {:?}", existing.type_name(), value);
+    match existing {
+        Value::String(_) => Ok(Value::from(value)),
+        Value::Integer(_) => value
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| expected()),
+        Value::Float(_) => value
+            .parse::<f64>()
+            .map(Value::from)
+            .map_err(|_| expected()),
+        Value::Boolean(_) => value
+            .parse::<bool>()
+            .map(Value::from)
+            .map_err(|_| expected()),
+        _ => match value.parse::<Value>() {
+            Ok(parsed) if parsed.type_name() == existing.type_name() => Ok(parsed),
+            _ => Err(expected()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+
+    #[test]
+    fn set_keeps_comments_and_types() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.toml");
+        fs::write(
+            &path,
+            "# kit\n[kit]\nversion = \"1.0.0\"  # release\ncount = 3\n",
+        )
+        .unwrap();
+        let mut file = MetadataFile::open(&path).unwrap();
+        file.set("kit.version", "1.0.1").unwrap();
+        file.set("kit.count", "4").unwrap();
+        assert!(file.set("kit.count", "four").is_err());
+        file.set("wifi.psk", "secret-pass").unwrap();
+        file.save().unwrap();
+
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with("# kit\n[kit]\nversion = \"1.0.1\"  # release\ncount = 4\n"));
+        let file = MetadataFile::open(&path).unwrap();
+        assert_eq!(
+            file.get("wifi.psk").and_then(Value::as_str),
+            Some("secret-pass")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let path = dir.join("bundle.toml");
+        fs::write(&path, "[wifi]\npsk = \"old-pass\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut file = MetadataFile::open(&path).unwrap();
+        file.set("wifi.psk", "new-pass").unwrap();
+        file.save().unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.join(".bundle.toml.tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_does_not_follow_links() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.toml");
+        fs::write(&path, "[wifi]\npsk = \"old-pass\"\n").unwrap();
+        let target = dir.join("elsewhere");
+        std::os::unix::fs::symlink(&target, dir.join(".bundle.toml.tmp")).unwrap();
+
+        let mut file = MetadataFile::open(&path).unwrap();
+        file.set("wifi.psk", "new-pass").unwrap();
+        assert!(file.save().is_err());
+        assert!(!target.exists());
+        assert!(fs::read_to_string(&path).unwrap().contains("old-pass"));
+    }
+
+    #[test]
+    fn uncommitted_files_are_removed() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.toml");
+        fs::write(&path, "[wifi]\npsk = \"old-pass\"\n").unwrap();
+        let mut file = MetadataFile::open(&path).unwrap();
+        file.set("wifi.psk", "new-pass").unwrap();
+
+        drop(file.stage().unwrap());
+        assert!(!dir.join(".bundle.toml.tmp").exists());
+        assert!(fs::read_to_string(&path).unwrap().contains("old-pass"));
+        file.stage().unwrap().commit().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("new-pass"));
+    }
+}
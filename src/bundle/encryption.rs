@@ -0,0 +1,221 @@
+//! age (X25519) encryption of bundles, either of the whole archive or of
+//! individual sensitive entries.
+//!
+//! Encrypted entries are stored with an `.age` suffix, and the manifest
+//! records their encrypted contents. Bundles can therefore be verified
+//! without any keys, while only a kit holding a matching identity can
+//! read the secrets inside.
+
+use super::{BundleError, Result};
+use age::stream::{StreamReader, StreamWriter};
+use age::{Decryptor, Encryptor};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+pub use age::x25519::{Identity, Recipient};
+
+/// Suffix given to encrypted entries inside the archive.
+pub const ENCRYPTED_SUFFIX: &str = ".age";
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+pub(crate) fn is_encrypted(prefix: &[u8]) -> bool {
+    prefix.starts_with(AGE_MAGIC)
+}
+
+pub fn parse_recipient(text: &str) -> Result<Recipient> {
+    Recipient::from_str(text.trim()).map_err(|_| BundleError::InvalidKey(text.trim().to_string()))
+}
+
+/// Parse an age identity, either on its own or from an identity file as
+/// written by `age-keygen`.
+pub fn parse_identity(text: &str) -> Result<Identity> {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|line| Identity::from_str(line).ok())
+        .ok_or_else(|| BundleError::InvalidKey(String::from("<age identity>")))
+}
+
+pub(crate) fn encrypt_writer<W: Write>(
+    recipients: &[Recipient],
+    writer: W,
+) -> Result<StreamWriter<W>> {
+    if recipients.is_empty() {
+        return Err(BundleError::NoRecipients);
+    }
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r as _))
+        .map_err(|e| BundleError::Encryption(e.to_string()))?;
+    Ok(encryptor.wrap_output(writer)?)
+}
+
+pub(crate) fn encrypt_reader<R: Read>(recipients: &[Recipient], mut reader: R) -> Result<Vec<u8>> {
+    let mut writer = encrypt_writer(recipients, Vec::new())?;
+    std::io::copy(&mut reader, &mut writer)?;
+    Ok(writer.finish()?)
+}
+
+pub(crate) fn decrypt_reader<R: Read>(
+    reader: R,
+    identities: &[Identity],
+) -> Result<StreamReader<R>> {
+    if identities.is_empty() {
+        return Err(BundleError::Encrypted);
+    }
+    Decryptor::new(reader)
+        .and_then(|decryptor| decryptor.decrypt(identities.iter().map(|i| i as _)))
+        .map_err(|e| match e {
+            age::DecryptError::NoMatchingKeys => BundleError::NoMatchingIdentity,
+            e => BundleError::Encryption(e.to_string()),
+        })
+}
+
+/// Decrypt an encrypted entry, such as one read from an extracted bundle.
+pub fn decrypt(data: &[u8], identities: &[Identity]) -> Result<Vec<u8>> {
+    let mut plaintext = Vec::new();
+    decrypt_reader(data, identities)?.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, TempDir};
+    use crate::bundle::{Bundle, Payload, Verification};
+    use std::fs;
+
+    const METADATA: &str = "[kit]\nname = \"kit\"\n";
+    const FILES: &[(&str, &str)] = &[("main.py", "print(1)\n")];
+
+    #[test]
+    fn whole_archive_round_trip() {
+        let dir = TempDir::new();
+        let identity = Identity::generate();
+        let path = dir.join("bundle.tar.gz.age");
+        builder(dir.path(), METADATA, FILES)
+            .encrypt_to(vec![identity.to_public()])
+            .encrypt_archive()
+            .build(&path)
+            .unwrap();
+        assert!(is_encrypted(&fs::read(&path).unwrap()));
+
+        let bundle = Bundle::open(&path).unwrap().with_identities(vec![identity]);
+        let info = bundle.info().unwrap();
+        assert!(info.encrypted);
+        assert_eq!(info.metadata["kit"]["name"].as_str(), Some("kit"));
+        assert_eq!(bundle.verify(&[]).unwrap(), Verification::Unsigned);
+
+        let extracted = dir.join("extracted");
+        bundle.extract_to(&extracted).unwrap();
+        assert_eq!(
+            fs::read_to_string(extracted.join("usercode/main.py")).unwrap(),
+            "print(1)\n"
+        );
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let dir = TempDir::new();
+        let identity = Identity::generate();
+        let secret = dir.join("secret");
+        fs::write(&secret, "psk = \"pass word\"\n").unwrap();
+        let path = dir.join("bundle.tar.gz");
+        builder(dir.path(), METADATA, FILES)
+            .encrypt_to(vec![identity.to_public()])
+            .add_encrypted_file(Payload::Config, &secret, "wifi.toml")
+            .unwrap()
+            .build(&path)
+            .unwrap();
+
+        // The bundle verifies without any identities, as the manifest
+        // records the encrypted contents.
+        let bundle = Bundle::open(&path).unwrap();
+        assert!(!bundle.is_encrypted().unwrap());
+        assert_eq!(bundle.verify(&[]).unwrap(), Verification::Unsigned);
+        let manifest = bundle.manifest().unwrap();
+        assert!(manifest.get("config/wifi.toml.age").is_some());
+        assert!(matches!(
+            bundle.decrypt_entry("config/wifi.toml"),
+            Err(BundleError::Encrypted)
+        ));
+
+        let bundle = bundle.with_identities(vec![identity]);
+        assert_eq!(
+            bundle.decrypt_entry("config/wifi.toml").unwrap(),
+            b"psk = \"pass word\"\n"
+        );
+        assert!(matches!(
+            bundle.decrypt_entry("config/other.toml"),
+            Err(BundleError::NoSuchEntry(_))
+        ));
+    }
+
+    #[test]
+    fn needs_identities() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.tar.gz.age");
+        builder(dir.path(), METADATA, FILES)
+            .encrypt_to(vec![Identity::generate().to_public()])
+            .encrypt_archive()
+            .build(&path)
+            .unwrap();
+
+        let bundle = Bundle::open(&path).unwrap();
+        assert!(bundle.is_encrypted().unwrap());
+        assert!(matches!(bundle.info(), Err(BundleError::Encrypted)));
+
+        let bundle = bundle.with_identities(vec![Identity::generate()]);
+        assert!(matches!(
+            bundle.info(),
+            Err(BundleError::NoMatchingIdentity)
+        ));
+    }
+
+    #[test]
+    fn needs_recipients() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.tar.gz.age");
+        let result = builder(dir.path(), METADATA, FILES)
+            .encrypt_archive()
+            .build(&path);
+        assert!(matches!(result, Err(BundleError::NoRecipients)));
+        assert!(!path.exists());
+
+        let secret = dir.join("secret");
+        fs::write(&secret, "secret").unwrap();
+        assert!(matches!(
+            builder(dir.path(), METADATA, FILES).add_encrypted_file(
+                Payload::Config,
+                &secret,
+                "secret"
+            ),
+            Err(BundleError::NoRecipients)
+        ));
+    }
+
+    #[test]
+    fn parses_keys() {
+        let identity = Identity::generate();
+        let text = format!(
+            "# created: today\n# public key: {}\n{}\n",
+            identity.to_public(),
+            age::secrecy::ExposeSecret::expose_secret(&identity.to_string())
+        );
+        let parsed = parse_identity(&text).unwrap();
+        assert_eq!(
+            parsed.to_public().to_string(),
+            identity.to_public().to_string()
+        );
+        assert_eq!(
+            parse_recipient(&format!(" {}\n", identity.to_public()))
+                .unwrap()
+                .to_string(),
+            identity.to_public().to_string()
+        );
+        assert!(matches!(
+            parse_recipient("age1nope"),
+            Err(BundleError::InvalidKey(_))
+        ));
+        assert!(parse_identity("# nothing here\n").is_err());
+    }
+}
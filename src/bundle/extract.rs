@@ -0,0 +1,395 @@
+use super::{Architecture, Bundle, BundleError, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Bounds on what extraction will write, guarding against archives built
+/// to fill the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractLimits {
+    pub max_entry_size: u64,
+    pub max_total_size: u64,
+    pub max_entries: usize,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_entry_size: 8 << 30,
+            max_total_size: 16 << 30,
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractedFile {
+    /// Path relative to the extraction directory.
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// The files written by an extraction, in archive order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractReport {
+    pub files: Vec<ExtractedFile>,
+}
+
+impl ExtractReport {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+impl Bundle {
+    /// Unpack the bundle into `dir` with the default limits.
+    pub fn extract_to<P: AsRef<Path>>(&self, dir: P) -> Result<ExtractReport> {
+        self.extract_to_with_limits(dir, ExtractLimits::default())
+    }
+
+    /// Unpack the bundle into `dir`.
+    ///
+    /// Only regular files and directories are extracted. Links and special
+    /// files, absolute paths, `..` components, and paths which would pass
+    /// through an existing symlink in `dir` are all rejected, as are
+    /// entries over `limits`. Permissions are limited to the usual
+    /// read/write/execute bits.
+    ///
+    /// When running on a robot architecture, bundles built for another
+    /// are refused; see [`Bundle::check_architecture`].
+    pub fn extract_to_with_limits<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        limits: ExtractLimits,
+    ) -> Result<ExtractReport> {
+        if let Some(host) = Architecture::host() {
+            self.check_architecture(host)?;
+        }
+        extract_archive(&mut self.archive()?, dir.as_ref(), limits, &[])
+    }
+
+    /// Refuse the bundle if it was built for another architecture than
+    /// `architecture`. Bundles without architecture-specific payloads
+    /// suit any.
+    pub fn check_architecture(&self, architecture: Architecture) -> Result<()> {
+        match self.manifest()?.architecture {
+            Some(bundle) if bundle != architecture => Err(BundleError::WrongArchitecture {
+                bundle,
+                wanted: architecture,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Safely unpack `archive` into `dir`, ignoring any entries named in
+/// `skip`. See [`Bundle::extract_to_with_limits`].
+pub(crate) fn extract_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    dir: &Path,
+    limits: ExtractLimits,
+    skip: &[&str],
+) -> Result<ExtractReport> {
+    fs::create_dir_all(dir)?;
+    let mut report = ExtractReport::default();
+    let mut total = 0u64;
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= limits.max_entries {
+            return Err(BundleError::TooManyEntries(limits.max_entries));
+        }
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if skip.contains(&name.as_str()) {
+            continue;
+        }
+        let relative = safe_relative_path(&name)?;
+        let target = dir.join(&relative);
+        check_no_symlinks(dir, &relative, &name)?;
+
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if !kind.is_file() {
+            return Err(unsafe_entry(&name, "not a regular file or directory"));
+        }
+
+        let size = entry.header().size()?;
+        if size > limits.max_entry_size {
+            return Err(BundleError::EntryTooLarge {
+                path: name,
+                size,
+                limit: limits.max_entry_size,
+            });
+        }
+        total += size;
+        if total > limits.max_total_size {
+            return Err(BundleError::TooLarge(limits.max_total_size));
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&target)?;
+        let written = io::copy(&mut (&mut entry).take(size), &mut file)?;
+        if written != size {
+            return Err(unsafe_entry(&name, "shorter than its header claims"));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = entry.header().mode()? & 0o777;
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        report.files.push(ExtractedFile {
+            path: relative,
+            size,
+        });
+    }
+
+    Ok(report)
+}
+
+fn unsafe_entry(path: &str, reason: &'static str) -> BundleError {
+    BundleError::UnsafeEntry {
+        path: path.to_string(),
+        reason,
+    }
+}
+
+pub(crate) fn safe_relative_path(name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(unsafe_entry(name, "path escapes the extraction directory")),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(unsafe_entry(name, "empty path"));
+    }
+    Ok(path)
+}
+
+/// Refuse to write through anything under `dir` which is already a
+/// symlink, as it may point outside of it.
+pub(crate) fn check_no_symlinks(dir: &Path, relative: &Path, name: &str) -> Result<()> {
+    let mut current = dir.to_path_buf();
+    for part in relative.iter() {
+        current.push(part);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(unsafe_entry(name, "path passes through a symlink"));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+    use tar::{EntryType, Header};
+
+    /// A tarball holding `entries`, each written with its name as it is,
+    /// without the checks `tar::Builder` makes on paths.
+    fn tarball(entries: &[(&str, EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, kind, data) in entries {
+            let mut header = Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            if kind.is_symlink() || kind.is_hard_link() {
+                header.set_link_name("/etc/passwd").unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn extract(data: &[u8], dir: &Path, limits: ExtractLimits) -> Result<ExtractReport> {
+        extract_archive(&mut tar::Archive::new(data), dir, limits, &[])
+    }
+
+    fn file<'a>(name: &'a str, data: &'a [u8]) -> (&'a str, EntryType, &'a [u8]) {
+        (name, EntryType::Regular, data)
+    }
+
+    fn assert_unsafe(result: Result<ExtractReport>, expected: &str) {
+        match result {
+            Err(BundleError::UnsafeEntry { path, .. }) => assert_eq!(path, expected),
+            other => panic!("expected {} to be refused, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn extracts_files_and_directories() {
+        let dir = TempDir::new();
+        let data = tarball(&[
+            ("usercode/", EntryType::Directory, b""),
+            file("usercode/main.py", b"print(1)\n"),
+            file("./config/robot.toml", b"x = 1\n"),
+        ]);
+        let report = extract(&data, &dir.join("out"), ExtractLimits::default()).unwrap();
+        assert_eq!(
+            report.files,
+            vec![
+                ExtractedFile {
+                    path: PathBuf::from("usercode/main.py"),
+                    size: 9,
+                },
+                ExtractedFile {
+                    path: PathBuf::from("config/robot.toml"),
+                    size: 6,
+                },
+            ]
+        );
+        assert_eq!(
+            fs::read(dir.join("out/usercode/main.py")).unwrap(),
+            b"print(1)\n"
+        );
+    }
+
+    #[test]
+    fn refuses_parent_components() {
+        let dir = TempDir::new();
+        let data = tarball(&[file("usercode/../../escaped", b"x")]);
+        assert_unsafe(
+            extract(&data, &dir.join("out"), ExtractLimits::default()),
+            "usercode/../../escaped",
+        );
+        assert!(!dir.join("escaped").exists());
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        let dir = TempDir::new();
+        let target = dir.join("absolute");
+        let name = target.to_str().unwrap();
+        let data = tarball(&[file(name, b"x")]);
+        assert_unsafe(
+            extract(&data, &dir.join("out"), ExtractLimits::default()),
+            name,
+        );
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn refuses_links() {
+        let dir = TempDir::new();
+        for kind in [EntryType::Symlink, EntryType::Link] {
+            let data = tarball(&[("usercode/link", kind, b"")]);
+            assert_unsafe(
+                extract(&data, &dir.join("out"), ExtractLimits::default()),
+                "usercode/link",
+            );
+            assert!(fs::symlink_metadata(dir.join("out/usercode/link")).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_writing_through_existing_symlinks() {
+        let dir = TempDir::new();
+        let out = dir.join("out");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&out).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, out.join("usercode")).unwrap();
+
+        let data = tarball(&[file("usercode/main.py", b"x")]);
+        assert_unsafe(
+            extract(&data, &out, ExtractLimits::default()),
+            "usercode/main.py",
+        );
+        assert!(!outside.join("main.py").exists());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let dir = TempDir::new();
+        let out = dir.join("out");
+        let data = tarball(&[file("a", b"12345"), file("b", b"12345")]);
+
+        let limits = ExtractLimits {
+            max_entry_size: 4,
+            ..ExtractLimits::default()
+        };
+        assert!(matches!(
+            extract(&data, &out, limits),
+            Err(BundleError::EntryTooLarge {
+                size: 5,
+                limit: 4,
+                ..
+            })
+        ));
+
+        let limits = ExtractLimits {
+            max_total_size: 8,
+            ..ExtractLimits::default()
+        };
+        assert!(matches!(
+            extract(&data, &out, limits),
+            Err(BundleError::TooLarge(8))
+        ));
+
+        let limits = ExtractLimits {
+            max_entries: 1,
+            ..ExtractLimits::default()
+        };
+        assert!(matches!(
+            extract(&data, &out, limits),
+            Err(BundleError::TooManyEntries(1))
+        ));
+    }
+
+    #[test]
+    fn refuses_entries_shorter_than_their_header() {
+        let dir = TempDir::new();
+        let mut data = tarball(&[file("usercode/big", &[7; 2000])]);
+        // Keep the header and just the first block of data.
+        data.truncate(1024);
+        assert_unsafe(
+            extract(&data, &dir.join("out"), ExtractLimits::default()),
+            "usercode/big",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strips_special_permission_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o4755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "usercode/run", &b"x"[..])
+            .unwrap();
+        let data = builder.into_inner().unwrap();
+
+        extract(&data, &dir.join("out"), ExtractLimits::default()).unwrap();
+        let mode = fs::metadata(dir.join("out/usercode/run"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
@@ -0,0 +1,123 @@
+use super::{BundleError, Manifest, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStage {
+    /// Before anything is packaged.
+    PreBuild,
+    /// Once the bundle has been written and signed.
+    PostBuild,
+}
+
+impl HookStage {
+    fn name(self) -> &'static str {
+        match self {
+            HookStage::PreBuild => "pre_build",
+            HookStage::PostBuild => "post_build",
+        }
+    }
+}
+
+/// What a hook is told about the build. Paths are absolute, as hooks
+/// may run in another directory.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HookContext {
+    pub stage: HookStage,
+    /// Where the bundle is being written.
+    pub output: PathBuf,
+    /// The bundle TOML.
+    pub metadata: PathBuf,
+    /// The bundle's manifest, once it has been built.
+    pub manifest: Option<Manifest>,
+}
+
+type HookFn = Box<dyn Fn(&HookContext) -> Result<()> + Send + Sync>;
+
+/// Callbacks run around a build by [`BundleBuilder`](super::BundleBuilder).
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) pre_build: Vec<HookFn>,
+    pub(crate) post_build: Vec<HookFn>,
+}
+
+impl Hooks {
+    pub(crate) fn run(&self, context: &HookContext) -> Result<()> {
+        let hooks = match context.stage {
+            HookStage::PreBuild => &self.pre_build,
+            HookStage::PostBuild => &self.post_build,
+        };
+        hooks.iter().try_for_each(|hook| hook(context))
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("pre_build", &self.pre_build.len())
+            .field("post_build", &self.post_build.len())
+            .finish()
+    }
+}
+
+/// Hook commands from a build configuration, each given as a program and
+/// its arguments:
+///
+/// ```toml
+/// [hooks]
+/// pre_build = [["python3", "scripts/calibrate.py"]]
+/// post_build = [["./upload.sh", "--team", "ABC"]]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookCommands {
+    #[serde(default)]
+    pub pre_build: Vec<Vec<String>>,
+    #[serde(default)]
+    pub post_build: Vec<Vec<String>>,
+}
+
+/// Run a hook command in `dir`.
+///
+/// The context is passed as JSON on stdin, and the stage, output and
+/// metadata paths in the `BUNDLE_HOOK`, `BUNDLE_OUTPUT` and
+/// `BUNDLE_METADATA` environment variables. A non-zero exit fails the
+/// build.
+pub fn run_command(command: &[String], dir: &Path, context: &HookContext) -> Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| BundleError::Hook("empty hook command".to_string()))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .env("BUNDLE_HOOK", context.stage.name())
+        .env("BUNDLE_OUTPUT", &context.output)
+        .env("BUNDLE_METADATA", &context.metadata)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| BundleError::Hook(format!("{}: {}", command.join(" "), e)))?;
+
+    let json = serde_json::to_vec(context).expect("hook context serializes");
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    match stdin.write_all(&json) {
+        // Hooks which don't care about the context needn't read it.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+    drop(stdin);
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(BundleError::Hook(format!(
+            "{} exited with {}",
+            command.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
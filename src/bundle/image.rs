@@ -0,0 +1,130 @@
+//! Filesystem images of bundles, ready to be written straight to an SD
+//! card or USB stick.
+//!
+//! [`write_image`] extracts a bundle and builds an image file of the
+//! given size from it, with the same files extraction would write. The
+//! images are assembled with the usual tools, which must be installed:
+//! `mke2fs` from e2fsprogs for ext4, and `mkfs.fat` from dosfstools and
+//! `mcopy` from mtools for FAT32.
+
+use super::steps::Staging;
+use super::{Bundle, BundleError, ExtractReport, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// The filesystems images can be made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Filesystem {
+    /// Readable by almost anything, but without permissions, so
+    /// executables in the payload lose their executable bit.
+    Fat32,
+    Ext4,
+}
+
+/// How to make an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageOptions {
+    pub filesystem: Filesystem,
+    /// Size of the image file, in bytes.
+    pub size: u64,
+    /// Volume label. FAT32 labels are at most 11 characters.
+    pub label: Option<String>,
+}
+
+impl ImageOptions {
+    pub fn new(filesystem: Filesystem, size: u64) -> Self {
+        ImageOptions {
+            filesystem,
+            size,
+            label: None,
+        }
+    }
+
+    pub fn label<L: Into<String>>(mut self, label: L) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+}
+
+/// Write a filesystem image holding `bundle`'s files to `output`, which
+/// is removed again if it can't be made.
+pub fn write_image<P: AsRef<Path>>(
+    bundle: &Bundle,
+    output: P,
+    options: &ImageOptions,
+) -> Result<ExtractReport> {
+    let output = output.as_ref();
+    let staging = Staging::create()?;
+    let report = bundle.extract_to(staging.path())?;
+    if report.total_size() > options.size {
+        return Err(BundleError::Image(format!(
+            "{} bytes of files won't fit in a {} byte image",
+            report.total_size(),
+            options.size
+        )));
+    }
+
+    let result = match options.filesystem {
+        Filesystem::Ext4 => write_ext4(staging.path(), output, options),
+        Filesystem::Fat32 => write_fat32(staging.path(), output, options),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result.map(|()| report)
+}
+
+fn write_ext4(dir: &Path, output: &Path, options: &ImageOptions) -> Result<()> {
+    File::create(output)?.set_len(options.size)?;
+    let mut command = Command::new("mke2fs");
+    command
+        .args(["-q", "-F", "-t", "ext4", "-E", "root_owner=0:0", "-d"])
+        .arg(dir);
+    if let Some(label) = &options.label {
+        command.arg("-L").arg(label);
+    }
+    run(command.arg(output))
+}
+
+fn write_fat32(dir: &Path, output: &Path, options: &ImageOptions) -> Result<()> {
+    // mkfs.fat makes the file itself, given its size in KiB.
+    let _ = fs::remove_file(output);
+    let mut command = Command::new("mkfs.fat");
+    command.args(["-C", "-F", "32"]);
+    if let Some(label) = &options.label {
+        command.arg("-n").arg(label);
+    }
+    run(command.arg(output).arg((options.size / 1024).to_string()))?;
+
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    entries.sort();
+    run(Command::new("mcopy")
+        .args(["-s", "-p", "-m", "-i"])
+        .arg(output)
+        .args(entries)
+        .arg("::/"))
+}
+
+/// Run a command, failing with its output if it fails.
+pub(crate) fn run(command: &mut Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| BundleError::Image(format!("{}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(BundleError::Image(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use super::{BundleError, Result};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the files declaring the licenses of what's in an asset
+/// directory. They are never bundled themselves.
+///
+/// ```toml
+/// [[licenses]]
+/// name = "Power board firmware"
+/// version = "1.4.0"
+/// license = "LicenseRef-Acme-Firmware"
+/// text = "LICENSE.acme"
+/// ```
+///
+/// `text` is a path relative to the declaring file.
+pub const LICENSES_FILENAME: &str = ".bundlelicenses";
+
+/// Path of the aggregated license document inside the archive.
+pub const LICENSES_PATH: &str = "LICENSES";
+
+/// The license of something in a bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct License {
+    pub name: String,
+    pub version: Option<String>,
+    /// SPDX licence expression, such as `MIT` or `LicenseRef-Vendor`.
+    pub license: String,
+    /// The full license text, for licenses which require it to be shipped.
+    pub text: Option<String>,
+    /// The archive directory it applies to, if declared in an asset.
+    pub location: Option<String>,
+}
+
+impl License {
+    pub fn new<N: Into<String>, L: Into<String>>(name: N, license: L) -> Self {
+        License {
+            name: name.into(),
+            version: None,
+            license: license.into(),
+            text: None,
+            location: None,
+        }
+    }
+
+    pub fn version<V: Into<String>>(mut self, version: V) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn text<T: Into<String>>(mut self, text: T) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Declarations {
+    #[serde(default)]
+    licenses: Vec<Declared>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Declared {
+    name: String,
+    #[serde(default)]
+    version: Option<String>,
+    license: String,
+    #[serde(default)]
+    text: Option<PathBuf>,
+}
+
+/// Read the licenses declared in `path`, which apply to the archive
+/// directory `location`.
+pub(crate) fn read_declarations(path: &Path, location: &str) -> Result<Vec<License>> {
+    let declarations: Declarations = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| BundleError::Licenses(path.to_path_buf(), e))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    declarations
+        .licenses
+        .into_iter()
+        .map(|declared| {
+            let text = match declared.text {
+                Some(text) => Some(fs::read_to_string(base.join(text))?),
+                None => None,
+            };
+            Ok(License {
+                name: declared.name,
+                version: declared.version,
+                license: declared.license,
+                text,
+                location: Some(location.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Write the `LICENSES` document: a heading for each license, followed by
+/// its text if there is one.
+pub(crate) fn generate(licenses: &[License]) -> String {
+    let mut doc = String::from("Licenses of the software and firmware in this bundle.\n");
+    for license in licenses {
+        write_license(&mut doc, license).expect("writing to a string");
+    }
+    doc
+}
+
+fn write_license(doc: &mut String, license: &License) -> std::fmt::Result {
+    writeln!(doc, "\n{}", "=".repeat(72))?;
+    match &license.version {
+        Some(version) => writeln!(doc, "{} {}", license.name, version)?,
+        None => writeln!(doc, "{}", license.name)?,
+    }
+    writeln!(doc, "License: {}", license.license)?;
+    if let Some(location) = &license.location {
+        writeln!(doc, "Location: {}", location)?;
+    }
+    if let Some(text) = &license.text {
+        writeln!(doc, "{}\n", "-".repeat(72))?;
+        writeln!(doc, "{}", text.trim_end())?;
+    }
+    Ok(())
+}
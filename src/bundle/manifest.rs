@@ -0,0 +1,171 @@
+use super::{Architecture, Payload, FORMAT_VERSION};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// A single file recorded in a bundle's manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Path, size and SHA-256 of every entry in a bundle archive, stored in
+/// the archive as `manifest.toml`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// The archive format version; see [`FORMAT_VERSION`].
+    #[serde(default = "unversioned_format")]
+    pub format: u32,
+    /// What the bundle was built for, if it has architecture-specific
+    /// payloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<Architecture>,
+    #[serde(rename = "files", default)]
+    pub entries: Vec<ManifestEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<Component>,
+}
+
+/// Manifests written before the format was versioned are version 1.
+fn unversioned_format() -> u32 {
+    1
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            format: FORMAT_VERSION,
+            architecture: None,
+            entries: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+}
+
+/// A payload's own record in the manifest, so it can be versioned and
+/// checked separately from the rest of the bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Component {
+    #[serde(rename = "name")]
+    pub payload: Payload,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// See [`Manifest::component_digest`].
+    pub sha256: String,
+}
+
+impl Manifest {
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn component(&self, payload: Payload) -> Option<&Component> {
+        self.components.iter().find(|c| c.payload == payload)
+    }
+
+    /// Hex SHA-256 over the path, size and hash of each of `payload`'s
+    /// entries, in manifest order, one per line and separated by spaces.
+    pub fn component_digest(&self, payload: Payload) -> String {
+        let mut hasher = Sha256::new();
+        for entry in &self.entries {
+            if Payload::of_path(&entry.path) == Some(payload) {
+                hasher.update(format!("{} {} {}\n", entry.path, entry.size, entry.sha256));
+            }
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = toml::de::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s)
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let text = toml::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&text)
+    }
+}
+
+/// Wraps a reader, hashing everything read through it.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Size and hex SHA-256 of the data read so far.
+    pub(crate) fn finish(self) -> (u64, String) {
+        (self.size, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The inner writer and hex SHA-256 of the data written.
+    pub(crate) fn finish(self) -> (W, String) {
+        (self.inner, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Size and hex SHA-256 of everything `reader` yields.
+pub(crate) fn hash_reader<R: Read>(reader: R) -> io::Result<(u64, String)> {
+    let mut reader = HashingReader::new(reader);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
@@ -0,0 +1,364 @@
+//! Bundle archives: the bundle TOML plus payload files, packed into a
+//! single compressed tarball (gzip by default, or zstd or xz), or a zip
+//! file.
+//!
+//! Archive layout:
+//!
+//! ```text
+//! bundle.toml     bundle metadata, always the first entry
+//! manifest.toml   path, size and SHA-256 of every other entry
+//! manifest.sig    ed25519 signature over manifest.toml, if signed
+//! manifest.asc    OpenPGP signature over manifest.toml, if signed
+//! sbom.spdx       SPDX software bill of materials, if generated
+//! LICENSES        licenses of the bundle's contents, if any are declared
+//! overlay/...     files overlaid onto the robot OS
+//! firmware/...    board firmware images
+//! usercode/...    the team's code
+//! config/...      robot configuration
+//! ```
+//!
+//! Bundles with architecture-specific payloads are built as a variant
+//! per [`Architecture`], recorded in the manifest and checked on install.
+//!
+//! Each payload directory is a component of the bundle, with its own
+//! entry in the manifest recording its version and a hash over its files,
+//! so components can be checked, reported on and updated separately.
+//!
+//! Payload entries may be individually encrypted, or the whole archive
+//! may be; see [`encryption`]. Archives may also be split into parts; see
+//! [`split`]. A signed attestation of how a bundle was built can be
+//! written alongside it; see [`provenance`].
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+mod assets;
+mod budget;
+mod builder;
+mod checksums;
+mod compression;
+mod config;
+mod container;
+pub mod delta;
+pub mod edit;
+pub mod encryption;
+mod extract;
+pub mod hooks;
+pub mod image;
+mod licenses;
+mod manifest;
+pub mod oci;
+#[cfg(feature = "openpgp")]
+pub mod openpgp;
+pub mod pi_image;
+pub mod provenance;
+mod reader;
+pub mod sbom;
+pub mod signing;
+pub mod split;
+pub mod steps;
+mod store;
+#[cfg(test)]
+mod testing;
+pub mod tuf;
+mod verify;
+pub mod version;
+
+pub use assets::{Asset, IGNORE_FILENAME};
+pub use budget::{Exceeded, OverBudget, SizeBudget};
+pub use builder::BundleBuilder;
+pub use checksums::{SUMS_FILENAME, SUMS_SIGNATURE_FILENAME};
+pub use compression::{Codec, Compression};
+pub use config::{BuildConfig, SbomConfig};
+pub use container::Container;
+pub use extract::{ExtractLimits, ExtractReport, ExtractedFile};
+pub use licenses::{License, LICENSES_FILENAME, LICENSES_PATH};
+pub use manifest::{Component, Manifest, ManifestEntry};
+pub use reader::{Bundle, BundleInfo};
+pub use store::BlobStore;
+pub use verify::{Problem, Verification};
+
+/// The archive format version written into every manifest.
+///
+/// This goes up whenever the layout changes in a way older readers would
+/// misread. Readers accept versions from [`MIN_FORMAT_VERSION`] up to
+/// this, and refuse anything newer rather than guess at it.
+///
+/// | Version | Changes |
+/// |---------|---------|
+/// | 1       | Initial format. Manifests from before versioning are treated as version 1. |
+/// | 2       | Manifests may record the [`Architecture`] a bundle is built for. |
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The oldest archive format version this version can still read.
+pub const MIN_FORMAT_VERSION: u32 = 1;
+
+/// Path of the bundle metadata inside the archive.
+pub const METADATA_PATH: &str = "bundle.toml";
+
+/// Path of the manifest inside the archive.
+pub const MANIFEST_PATH: &str = "manifest.toml";
+
+/// Path of the manifest signature inside the archive.
+pub const SIGNATURE_PATH: &str = "manifest.sig";
+
+/// Path of the OpenPGP manifest signature inside the archive.
+pub const OPENPGP_SIGNATURE_PATH: &str = "manifest.asc";
+
+/// A string in the `[kit]` table of bundle metadata, such as `name` or
+/// `version`.
+pub(crate) fn kit_field<'a>(metadata: &'a toml::Table, key: &str) -> Option<&'a str> {
+    metadata.get("kit")?.get(key)?.as_str()
+}
+
+/// The kinds of payload a bundle carries, each stored under its own
+/// top-level directory in the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    Overlay,
+    Firmware,
+    Usercode,
+    Config,
+}
+
+impl Payload {
+    pub const ALL: [Payload; 4] = [
+        Payload::Overlay,
+        Payload::Firmware,
+        Payload::Usercode,
+        Payload::Config,
+    ];
+
+    pub fn directory(self) -> &'static str {
+        match self {
+            Payload::Overlay => "overlay",
+            Payload::Firmware => "firmware",
+            Payload::Usercode => "usercode",
+            Payload::Config => "config",
+        }
+    }
+
+    /// The payload an in-archive path belongs to.
+    pub fn of_path(path: &str) -> Option<Self> {
+        let directory = path.split('/').next()?;
+        Payload::ALL
+            .iter()
+            .copied()
+            .find(|payload| payload.directory() == directory)
+    }
+}
+
+/// The CPU architectures robots run on. Bundles with architecture-specific
+/// payloads are built as a variant for each, recording it in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Architecture {
+    /// 32-bit ARM, such as kits based on the Pi 3.
+    Armhf,
+    /// 64-bit ARM, such as kits based on the Pi 4 and 5.
+    Arm64,
+}
+
+impl Architecture {
+    pub const ALL: [Architecture; 2] = [Architecture::Armhf, Architecture::Arm64];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Architecture::Armhf => "armhf",
+            Architecture::Arm64 => "arm64",
+        }
+    }
+
+    /// The architecture this program was built for, if it is one robots
+    /// run on.
+    pub fn host() -> Option<Self> {
+        if cfg!(target_arch = "aarch64") {
+            Some(Architecture::Arm64)
+        } else if cfg!(target_arch = "arm") {
+            Some(Architecture::Armhf)
+        } else {
+            None
+        }
+    }
+
+    /// Where to write this architecture's variant of a bundle built to
+    /// `output`: `bundle.tar.gz` becomes `bundle-arm64.tar.gz`.
+    pub fn variant_path<P: AsRef<Path>>(self, output: P) -> PathBuf {
+        let output = output.as_ref();
+        let name = output
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match name.split_once('.') {
+            Some((stem, extension)) => format!("{}-{}.{}", stem, self.name(), extension),
+            None => format!("{}-{}", name, self.name()),
+        };
+        output.with_file_name(name)
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid bundle metadata: {0}")]
+    Metadata(#[from] toml::de::Error),
+
+    #[error("invalid bundle metadata: {0}")]
+    MetadataDocument(#[from] toml_edit::TomlError),
+
+    #[error("invalid kit version {0:?}, expected one like 1.2.3, or 1:1.2.3 with an epoch")]
+    InvalidVersion(String),
+
+    #[error("cannot set {field}: {reason}")]
+    Field { field: String, reason: String },
+
+    #[error("invalid path inside bundle: {0}")]
+    InvalidPath(PathBuf),
+
+    #[error("invalid build configuration: {0}")]
+    Config(toml::de::Error),
+
+    #[error("invalid license declarations in {0}: {1}")]
+    Licenses(PathBuf, toml::de::Error),
+
+    #[error("build hook failed: {0}")]
+    Hook(String),
+
+    #[error("build step {0} failed: {1}")]
+    Step(String, Box<BundleError>),
+
+    #[error("invalid glob pattern: {0}")]
+    InvalidGlob(String),
+
+    #[error("duplicate bundle entry: {0}")]
+    DuplicateEntry(String),
+
+    #[error("{0}")]
+    OverBudget(OverBudget),
+
+    #[error("{0} changed while building bundle")]
+    ChangedDuringBuild(String),
+
+    #[error("{1} is not a valid {0:?} compression level")]
+    InvalidCompressionLevel(Codec, u32),
+
+    #[error("not a gzip, zstd or xz compressed bundle")]
+    UnknownCompression,
+
+    #[error("zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("not supported for zip bundles: {0}")]
+    UnsupportedZip(String),
+
+    #[error("bundle is encrypted, but no identities were given to decrypt it")]
+    Encrypted,
+
+    #[error("bundle is not encrypted to any of the given identities")]
+    NoMatchingIdentity,
+
+    #[error("encryption requires at least one recipient")]
+    NoRecipients,
+
+    #[error("encryption error: {0}")]
+    Encryption(String),
+
+    #[error("cannot make a delta of an encrypted bundle")]
+    EncryptedDelta,
+
+    #[error("cannot export an encrypted bundle as an OCI image")]
+    EncryptedExport,
+
+    #[error("bundle is built for {bundle}, not {wanted}")]
+    WrongArchitecture {
+        bundle: Architecture,
+        wanted: Architecture,
+    },
+
+    #[error("bundle has no entry {0}")]
+    NoSuchEntry(String),
+
+    #[error("bundle produced by a newer bundler (format version {0}, this one reads up to {max})", max = FORMAT_VERSION)]
+    NewerFormat(i64),
+
+    #[error("bundle format version {0} is no longer supported (oldest readable is {min})", min = MIN_FORMAT_VERSION)]
+    UnsupportedFormat(i64),
+
+    #[error("invalid bundle manifest: {0}")]
+    Manifest(toml::de::Error),
+
+    #[error("bundle is missing {0}")]
+    MissingEntry(&'static str),
+
+    #[error("{0} is not valid UTF-8")]
+    NotUtf8(&'static str),
+
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("malformed manifest signature")]
+    MalformedSignature,
+
+    #[error("bundle is not signed")]
+    Unsigned,
+
+    #[error("provenance attestations must be signed, but no signing key was given")]
+    UnsignedProvenance,
+
+    #[error("bundle is signed by an untrusted key: {0}")]
+    UntrustedKey(String),
+
+    #[error("bundle signature does not match its manifest")]
+    BadSignature,
+
+    #[error("invalid delta description: {0}")]
+    Delta(toml::de::Error),
+
+    #[error("invalid split archive index: {0}")]
+    Split(toml::de::Error),
+
+    #[error("could not make filesystem image: {0}")]
+    Image(String),
+
+    #[error("invalid OCI image layout: {0}")]
+    Oci(String),
+
+    #[error("invalid TUF repository: {0}")]
+    Tuf(String),
+
+    #[error("delta was not made against the installed bundle")]
+    DeltaBaseMismatch,
+
+    #[error("delta entry {0} does not match the new manifest")]
+    DeltaMismatch(String),
+
+    #[error("refusing to extract {path}: {reason}")]
+    UnsafeEntry { path: String, reason: &'static str },
+
+    #[error("bundle entry {path} is {size} bytes, over the {limit} byte limit")]
+    EntryTooLarge { path: String, size: u64, limit: u64 },
+
+    #[error("bundle contents exceed the {0} byte limit")]
+    TooLarge(u64),
+
+    #[error("bundle has more than {0} entries")]
+    TooManyEntries(usize),
+
+    #[cfg(feature = "openpgp")]
+    #[error("OpenPGP error: {0}")]
+    OpenPgp(String),
+}
+
+pub type Result<T> = std::result::Result<T, BundleError>;
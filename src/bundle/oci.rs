@@ -0,0 +1,232 @@
+//! Export of bundles as OCI images, so they can be distributed through
+//! container registries.
+//!
+//! [`export`] writes a bundle into an [OCI image layout] directory as an
+//! image with a single gzipped layer holding the bundle's entries, exactly
+//! as they are in the archive, so the manifest and its signatures come
+//! along and can still be checked once pulled. Several bundles, such as
+//! the variants for each architecture, can be exported to the same layout
+//! under different tags.
+//!
+//! Pushing the layout to a registry is left to existing tools, such as
+//! `skopeo copy oci:layout:tag docker://registry/robot/bundle:tag` or
+//! `oras cp --from-oci-layout layout:tag registry/robot/bundle:tag`.
+//!
+//! [OCI image layout]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use super::manifest::HashingWriter;
+use super::{kit_field, Architecture, Bundle, BundleError, Result};
+use flate2::write::GzEncoder;
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Export `bundle` to the OCI image layout at `layout`, which is created
+/// if need be, tagged as `tag`. Any image already there with that tag is
+/// replaced. Returns the digest of the image's manifest.
+///
+/// Bundles encrypted as a whole are refused, as the layer would hold
+/// their contents unencrypted.
+pub fn export<P: AsRef<Path>>(bundle: &Bundle, layout: P, tag: &str) -> Result<String> {
+    if bundle.is_encrypted()? {
+        return Err(BundleError::EncryptedExport);
+    }
+    let layout = layout.as_ref();
+    let blobs = layout.join("blobs").join("sha256");
+    fs::create_dir_all(&blobs)?;
+    fs::write(
+        layout.join("oci-layout"),
+        json!({ "imageLayoutVersion": "1.0.0" }).to_string(),
+    )?;
+
+    let info = bundle.info()?;
+    let (layer, diff_id) = write_layer(bundle, &blobs)?;
+    let platform = platform(info.manifest.architecture);
+
+    let mut config = platform.clone();
+    config["rootfs"] = json!({
+        "type": "layers",
+        "diff_ids": [format!("sha256:{}", diff_id)],
+    });
+    let mut labels = serde_json::Map::new();
+    for key in ["name", "version"] {
+        if let Some(value) = kit_field(&info.metadata, key) {
+            labels.insert(format!("org.opencontainers.image.{}", key), value.into());
+        }
+    }
+    config["config"] = json!({ "Labels": labels });
+    let config = write_blob(&blobs, CONFIG_MEDIA_TYPE, &to_vec(&config))?;
+
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "config": config,
+        "layers": [layer],
+    });
+    let mut manifest = write_blob(&blobs, MANIFEST_MEDIA_TYPE, &to_vec(&manifest))?;
+    let digest = manifest["digest"]
+        .as_str()
+        .expect("digest is set")
+        .to_string();
+    manifest["platform"] = platform;
+    manifest["annotations"] = json!({ REF_NAME: tag });
+    update_index(layout, tag, manifest)?;
+    Ok(digest)
+}
+
+/// OCI's names for the platform a bundle is for. Bundles for any
+/// architecture are given the one robots most often run.
+fn platform(architecture: Option<Architecture>) -> Value {
+    match architecture {
+        Some(Architecture::Armhf) => {
+            json!({ "architecture": "arm", "os": "linux", "variant": "v7" })
+        }
+        Some(Architecture::Arm64) | None => {
+            json!({ "architecture": "arm64", "os": "linux", "variant": "v8" })
+        }
+    }
+}
+
+/// Write the layer, returning its descriptor and the hex SHA-256 of its
+/// uncompressed tarball.
+fn write_layer(bundle: &Bundle, blobs: &Path) -> Result<(Value, String)> {
+    let temp = blobs.join(".layer.tmp");
+    let result = write_layer_to(bundle, &temp).and_then(|(digest, diff_id)| {
+        let size = fs::metadata(&temp)?.len();
+        fs::rename(&temp, blobs.join(&digest))?;
+        Ok((descriptor(LAYER_MEDIA_TYPE, &digest, size), diff_id))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Write the layer to `path`, returning the hex SHA-256 of it and of its
+/// uncompressed tarball.
+fn write_layer_to(bundle: &Bundle, path: &Path) -> Result<(String, String)> {
+    let file = HashingWriter::new(BufWriter::new(File::create(path)?));
+    let mut layer = tar::Builder::new(HashingWriter::new(GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    )));
+    let mut archive = bundle.archive()?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let mut header = entry.header().clone();
+        let path = entry.path()?.into_owned();
+        layer.append_data(&mut header, path, entry)?;
+    }
+    let (gz, diff_id) = layer.into_inner()?.finish();
+    let (file, digest) = gz.finish()?.finish();
+    file.into_inner()
+        .map_err(|e| BundleError::Io(e.into_error()))?
+        .sync_all()?;
+    Ok((digest, diff_id))
+}
+
+fn write_blob(blobs: &Path, media_type: &str, data: &[u8]) -> io::Result<Value> {
+    let mut writer = HashingWriter::new(Vec::new());
+    writer.write_all(data)?;
+    let (_, digest) = writer.finish();
+    fs::write(blobs.join(&digest), data)?;
+    Ok(descriptor(media_type, &digest, data.len() as u64))
+}
+
+fn descriptor(media_type: &str, digest: &str, size: u64) -> Value {
+    json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{}", digest),
+        "size": size,
+    })
+}
+
+/// Point `tag` at `manifest` in the layout's `index.json`.
+fn update_index(layout: &Path, tag: &str, manifest: Value) -> Result<()> {
+    let path = layout.join("index.json");
+    let mut index = match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| BundleError::Oci(format!("{}: {}", path.display(), e)))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => json!({
+            "schemaVersion": 2,
+            "manifests": [],
+        }),
+        Err(e) => return Err(e.into()),
+    };
+    let manifests = index
+        .get_mut("manifests")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| BundleError::Oci(format!("{} has no manifests", path.display())))?;
+    manifests.retain(|existing| existing["annotations"][REF_NAME].as_str() != Some(tag));
+    manifests.push(manifest);
+
+    let temp = layout.join(".index.json.tmp");
+    fs::write(&temp, to_vec(&index))?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+fn to_vec(value: &Value) -> Vec<u8> {
+    serde_json::to_vec(value).expect("JSON values serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::encryption::Identity;
+    use crate::bundle::testing::{builder, TempDir};
+
+    const METADATA: &str = "[kit]\nname = \"kit\"\nversion = \"1.2.3\"\n";
+    const FILES: &[(&str, &str)] = &[("main.py", "print(1)\n")];
+
+    #[test]
+    fn labels_from_kit() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.tar.gz");
+        builder(dir.path(), METADATA, FILES).build(&path).unwrap();
+        let layout = dir.join("layout");
+        let digest = export(&Bundle::open(&path).unwrap(), &layout, "latest").unwrap();
+
+        let blob = |digest: &str| -> Value {
+            let path = layout
+                .join("blobs/sha256")
+                .join(digest.trim_start_matches("sha256:"));
+            serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+        };
+        let manifest = blob(&digest);
+        let config = blob(manifest["config"]["digest"].as_str().unwrap());
+        assert_eq!(
+            config["config"]["Labels"],
+            json!({
+                "org.opencontainers.image.name": "kit",
+                "org.opencontainers.image.version": "1.2.3",
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_encrypted_bundles() {
+        let dir = TempDir::new();
+        let identity = Identity::generate();
+        let path = dir.join("bundle.tar.gz.age");
+        builder(dir.path(), METADATA, FILES)
+            .encrypt_to(vec![identity.to_public()])
+            .encrypt_archive()
+            .build(&path)
+            .unwrap();
+        let bundle = Bundle::open(&path).unwrap().with_identities(vec![identity]);
+
+        let layout = dir.join("layout");
+        assert!(matches!(
+            export(&bundle, &layout, "latest"),
+            Err(BundleError::EncryptedExport)
+        ));
+        assert!(!layout.exists());
+    }
+}
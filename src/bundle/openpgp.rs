@@ -0,0 +1,128 @@
+//! OpenPGP signing of bundle manifests, for keys which already live in
+//! GPG. The signature is stored ASCII-armoured, so an extracted bundle can
+//! be checked with `gpg --verify manifest.asc manifest.toml`.
+//!
+//! Sequoia is built with its Nettle backend rather than the pure Rust one,
+//! whose RSA isn't constant-time, so signing in CI doesn't leak the key
+//! through timing.
+
+use super::{BundleError, Result};
+use openpgp::cert::CertParser;
+use openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationError, VerificationHelper,
+};
+use openpgp::parse::Parse;
+use openpgp::policy::StandardPolicy;
+use openpgp::serialize::stream::{Armorer, Message, Signer};
+use openpgp::KeyHandle;
+use sequoia_openpgp as openpgp;
+use std::io::Write;
+
+pub use openpgp::Cert;
+
+fn pgp_error<E: std::fmt::Display>(error: E) -> BundleError {
+    BundleError::OpenPgp(error.to_string())
+}
+
+/// Read every certificate from an armoured or binary keyring.
+pub fn read_certs(bytes: &[u8]) -> Result<Vec<Cert>> {
+    CertParser::from_bytes(bytes)
+        .map_err(pgp_error)?
+        .map(|cert| cert.map_err(pgp_error))
+        .collect()
+}
+
+/// Produce an armoured detached signature over `data` using the first
+/// usable signing key in `cert`, which must hold unencrypted secret key
+/// material.
+pub fn sign_detached(cert: &Cert, data: &[u8]) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let keypair = cert
+        .keys()
+        .with_policy(&policy, None)
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .unencrypted_secret()
+        .next()
+        .ok_or_else(|| pgp_error(format!("no usable signing key in {}", cert.fingerprint())))?
+        .key()
+        .clone()
+        .into_keypair()
+        .map_err(pgp_error)?;
+
+    let mut signature = Vec::new();
+    let message = Armorer::new(Message::new(&mut signature))
+        .kind(openpgp::armor::Kind::Signature)
+        .build()
+        .map_err(pgp_error)?;
+    let mut signer = Signer::new(message, keypair)
+        .map_err(pgp_error)?
+        .detached()
+        .build()
+        .map_err(pgp_error)?;
+    signer.write_all(data)?;
+    signer.finalize().map_err(pgp_error)?;
+    Ok(signature)
+}
+
+/// Check `signature` was made over `data` by one of `trusted`, returning
+/// the fingerprint of the certificate which made it.
+pub fn verify_detached(signature: &[u8], data: &[u8], trusted: &[Cert]) -> Result<String> {
+    let policy = StandardPolicy::new();
+    let helper = Helper {
+        trusted,
+        signer: None,
+        outcome: None,
+    };
+    let mut verifier = DetachedVerifierBuilder::from_bytes(signature)
+        .map_err(|_| BundleError::MalformedSignature)?
+        .with_policy(&policy, None, helper)
+        .map_err(pgp_error)?;
+    let result = verifier.verify_bytes(data);
+    let helper = verifier.into_helper();
+    match (result, helper.signer) {
+        (Ok(()), Some(signer)) => Ok(signer),
+        _ => Err(helper.outcome.unwrap_or(BundleError::BadSignature)),
+    }
+}
+
+struct Helper<'a> {
+    trusted: &'a [Cert],
+    signer: Option<String>,
+    outcome: Option<BundleError>,
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self.trusted.to_vec())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                for result in results {
+                    match result {
+                        Ok(good) => {
+                            self.signer = Some(good.ka.cert().fingerprint().to_string());
+                            return Ok(());
+                        }
+                        Err(VerificationError::MissingKey { sig }) => {
+                            let issuer = sig
+                                .get_issuers()
+                                .first()
+                                .map(|handle| handle.to_string())
+                                .unwrap_or_default();
+                            self.outcome = Some(BundleError::UntrustedKey(issuer));
+                        }
+                        Err(VerificationError::MalformedSignature { .. }) => {
+                            self.outcome = Some(BundleError::MalformedSignature);
+                        }
+                        Err(_) => self.outcome = Some(BundleError::BadSignature),
+                    }
+                }
+            }
+        }
+        Err(openpgp::Error::InvalidOperation("no valid signature".into()).into())
+    }
+}
@@ -0,0 +1,285 @@
+//! Customised Raspberry Pi OS images, with a bundle already installed.
+//!
+//! [`PiImage`] copies a base Raspberry Pi OS image and writes into its
+//! partitions in place, without mounting anything, so it needs neither
+//! root nor loop devices. The bundle and any extra root files go into
+//! the ext4 root partition by way of `debugfs` from e2fsprogs, and boot
+//! files (such as `config.txt` or `ssh`) into the FAT boot partition by
+//! way of `mcopy` from mtools. The result can be flashed as it is.
+
+use super::image::run;
+use super::steps::Staging;
+use super::{Bundle, BundleError, ExtractReport, Result};
+use ignore::WalkBuilder;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// A base Raspberry Pi OS image, and what to add to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PiImage {
+    base: PathBuf,
+    install_dir: String,
+    root_files: Vec<(String, Vec<u8>)>,
+    boot_files: Vec<(String, Vec<u8>)>,
+}
+
+impl PiImage {
+    pub fn new<P: AsRef<Path>>(base: P) -> Self {
+        PiImage {
+            base: base.as_ref().to_path_buf(),
+            install_dir: "/opt/robot/bundle".to_string(),
+            root_files: Vec::new(),
+            boot_files: Vec::new(),
+        }
+    }
+
+    /// Where in the root filesystem to install the bundle, by default
+    /// `/opt/robot/bundle`. Files already there are overwritten, but any
+    /// the bundle doesn't have are left alone.
+    pub fn install_dir<D: Into<String>>(&mut self, dir: D) -> &mut Self {
+        self.install_dir = dir.into();
+        self
+    }
+
+    /// Write a file to an absolute path in the root filesystem, replacing
+    /// anything there, such as `/etc/hostname`.
+    pub fn root_file<P: Into<String>>(&mut self, path: P, contents: Vec<u8>) -> &mut Self {
+        self.root_files.push((path.into(), contents));
+        self
+    }
+
+    /// Write a file to the top of the boot partition, replacing anything
+    /// there, such as `config.txt`, or an empty `ssh` to turn SSH on.
+    pub fn boot_file<N: Into<String>>(&mut self, name: N, contents: Vec<u8>) -> &mut Self {
+        self.boot_files.push((name.into(), contents));
+        self
+    }
+
+    /// Write the customised image, with `bundle` installed, to `output`,
+    /// which is removed again if it can't be made.
+    pub fn write<P: AsRef<Path>>(&self, bundle: &Bundle, output: P) -> Result<ExtractReport> {
+        let output = output.as_ref();
+        fs::copy(&self.base, output)?;
+        let result = self.customise(bundle, output);
+        if result.is_err() {
+            let _ = fs::remove_file(output);
+        }
+        result
+    }
+
+    fn customise(&self, bundle: &Bundle, output: &Path) -> Result<ExtractReport> {
+        let partitions = Partitions::read(output)?;
+        let staging = Staging::create()?;
+        let extracted = staging.path().join("bundle");
+        let report = bundle.extract_to(&extracted)?;
+
+        let mut script = String::new();
+        let install_dir = absolute_path(&self.install_dir)?;
+        mkdir_all(&mut script, &install_dir)?;
+        let walker = WalkBuilder::new(&extracted)
+            .standard_filters(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            let entry = entry.map_err(|e| BundleError::Io(io::Error::other(e)))?;
+            let relative = entry
+                .path()
+                .strip_prefix(&extracted)
+                .expect("walker yields paths below its root");
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+            let relative = relative
+                .to_str()
+                .ok_or_else(|| BundleError::InvalidPath(relative.to_path_buf()))?;
+            let target = format!("{}/{}", install_dir, relative.replace('\\', "/"));
+            let metadata = entry
+                .metadata()
+                .map_err(|e| BundleError::Io(io::Error::other(e)))?;
+            if metadata.is_dir() {
+                mkdir(&mut script, &target)?;
+            } else {
+                write_file(&mut script, entry.path(), &target, file_mode(&metadata))?;
+            }
+        }
+
+        for (index, (path, contents)) in self.root_files.iter().enumerate() {
+            let path = absolute_path(path)?;
+            let source = staging.path().join(format!("root-{}", index));
+            fs::write(&source, contents)?;
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                mkdir_all(&mut script, parent)?;
+            }
+            write_file(&mut script, &source, &path, 0o644)?;
+        }
+
+        let script_path = staging.path().join("debugfs.script");
+        fs::write(&script_path, script)?;
+        debugfs(&script_path, output, partitions.root)?;
+
+        for (index, (name, contents)) in self.boot_files.iter().enumerate() {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(BundleError::InvalidPath(PathBuf::from(name)));
+            }
+            let source = staging.path().join(format!("boot-{}", index));
+            fs::write(&source, contents)?;
+            run(Command::new("mcopy")
+                .arg("-o")
+                .arg("-i")
+                .arg(format!("{}@@{}", output.display(), partitions.boot))
+                .arg(&source)
+                .arg(format!("::/{}", name)))?;
+        }
+        Ok(report)
+    }
+}
+
+/// Byte offsets of the boot and root partitions in an image.
+struct Partitions {
+    boot: u64,
+    root: u64,
+}
+
+impl Partitions {
+    /// Find the first FAT and Linux partitions in the image's MBR, which
+    /// is how Raspberry Pi OS images are laid out.
+    fn read(path: &Path) -> Result<Self> {
+        let mut mbr = [0; 512];
+        File::open(path)?.read_exact(&mut mbr)?;
+        if mbr[510..] != [0x55, 0xaa] {
+            return Err(BundleError::Image(
+                "base image has no MBR partition table".to_string(),
+            ));
+        }
+        let (mut boot, mut root) = (None, None);
+        for entry in mbr[446..510].chunks(16) {
+            let start = u64::from(u32::from_le_bytes(
+                entry[8..12].try_into().expect("slice is 4 bytes"),
+            ));
+            match entry[4] {
+                0x0b | 0x0c | 0x0e if boot.is_none() => boot = Some(start * SECTOR_SIZE),
+                0x83 if root.is_none() => root = Some(start * SECTOR_SIZE),
+                _ => {}
+            }
+        }
+        match (boot, root) {
+            (Some(boot), Some(root)) => Ok(Partitions { boot, root }),
+            _ => Err(BundleError::Image(
+                "base image lacks a FAT boot or Linux root partition".to_string(),
+            )),
+        }
+    }
+}
+
+/// Run a `debugfs` script against the ext4 filesystem at `offset`.
+fn debugfs(script: &Path, image: &Path, offset: u64) -> Result<()> {
+    let output = Command::new("debugfs")
+        .arg("-w")
+        .arg("-f")
+        .arg(script)
+        .arg(format!("{}?offset={}", image.display(), offset))
+        .output()
+        .map_err(|e| BundleError::Image(format!("debugfs: {}", e)))?;
+    // debugfs carries on past failed commands and exits successfully, so
+    // what went wrong has to be picked out of what it says. Directories
+    // which already exist and files which don't yet are expected.
+    let errors: Vec<&str> = std::str::from_utf8(&output.stderr)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| {
+            !line.starts_with("debugfs ")
+                && !line.contains("already exists")
+                && !line.contains("File not found by ext2_lookup")
+                && !line.trim().is_empty()
+        })
+        .collect();
+    if !output.status.success() || !errors.is_empty() {
+        return Err(BundleError::Image(format!(
+            "debugfs failed: {}",
+            errors.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+/// Make a directory from the bundle, and make it root's.
+fn mkdir(script: &mut String, path: &str) -> Result<()> {
+    let path = quote(path)?;
+    writeln!(script, "mkdir {}", path).expect("writing to a string");
+    set_owner(script, &path, 0o40755);
+    Ok(())
+}
+
+/// Make `path` and any of its parents which don't exist yet. debugfs
+/// makes new directories root's, with mode 0755, and leaves those which
+/// already exist as they are, so this doesn't change the owner or mode
+/// of existing directories such as `/home/pi` or `/tmp`.
+fn mkdir_all(script: &mut String, path: &str) -> Result<()> {
+    let mut current = String::new();
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        current.push('/');
+        current.push_str(part);
+        writeln!(script, "mkdir {}", quote(&current)?).expect("writing to a string");
+    }
+    Ok(())
+}
+
+fn write_file(script: &mut String, source: &Path, target: &str, mode: u32) -> Result<()> {
+    let source = source
+        .to_str()
+        .ok_or_else(|| BundleError::InvalidPath(source.to_path_buf()))?;
+    let target = quote(target)?;
+    writeln!(script, "rm {}", target).expect("writing to a string");
+    writeln!(script, "write {} {}", quote(source)?, target).expect("writing to a string");
+    set_owner(script, &target, 0o100000 | mode);
+    Ok(())
+}
+
+/// Make a file root's, with `mode` (including its type bits).
+fn set_owner(script: &mut String, quoted: &str, mode: u32) {
+    writeln!(script, "sif {} uid 0", quoted).expect("writing to a string");
+    writeln!(script, "sif {} gid 0", quoted).expect("writing to a string");
+    writeln!(script, "sif {} mode 0{:o}", quoted, mode).expect("writing to a string");
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0o644
+}
+
+/// Quote a path for a debugfs script, which has no way of escaping
+/// quotes themselves.
+fn quote(path: &str) -> Result<String> {
+    if path.contains(['"', '\n', '\r']) {
+        return Err(BundleError::InvalidPath(PathBuf::from(path)));
+    }
+    Ok(format!("\"{}\"", path))
+}
+
+/// Check a path in the root filesystem is absolute, without any `.` or
+/// `..` components, and drop any trailing slash.
+fn absolute_path(path: &str) -> Result<String> {
+    let invalid = || BundleError::InvalidPath(PathBuf::from(path));
+    let trimmed = path.trim_end_matches('/');
+    if !trimmed.starts_with('/')
+        || trimmed
+            .split('/')
+            .skip(1)
+            .any(|part| matches!(part, "" | "." | ".."))
+    {
+        return Err(invalid());
+    }
+    Ok(trimmed.to_string())
+}
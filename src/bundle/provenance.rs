@@ -0,0 +1,383 @@
+//! Build provenance: a signed [in-toto] attestation, in the [SLSA
+//! provenance] format, of what went into a bundle and what came out.
+//!
+//! The attestation is written next to the bundle, with
+//! [`PROVENANCE_SUFFIX`] added to its name, as a [DSSE] envelope signed
+//! with the bundle's ed25519 key. Its subject is the bundle file (and its
+//! parts, if split), and it records the hash of every input file, the
+//! source commit and the bundler version. Checking it against the key CI
+//! signs with shows a published bundle was built there.
+//!
+//! [in-toto]: https://in-toto.io/Statement/v1
+//! [SLSA provenance]: https://slsa.dev/provenance/v1
+//! [DSSE]: https://github.com/secure-systems-lab/dsse
+
+use super::signing::{decode_verifying_key, encode_key, SigningKey, VerifyingKey};
+use super::{BundleError, Manifest, Payload, Result, METADATA_PATH};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Added to a bundle's file name to name its attestation.
+pub const PROVENANCE_SUFFIX: &str = ".intoto.jsonl";
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+const BUILD_TYPE: &str = "https://github.com/RealOrangeOne/robot-bundler/build/v1";
+
+/// Who built a bundle, and from what.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// Identifies what ran the build, such as the CI workflow's URL.
+    pub builder_id: String,
+    /// The source repository, such as `git+https://github.com/team/robot`.
+    pub repository: Option<String>,
+    /// The commit of the repository the bundle was built from.
+    pub commit: Option<String>,
+    /// Identifies this particular build, such as the CI run's URL.
+    pub invocation_id: Option<String>,
+}
+
+impl Provenance {
+    pub fn new<B: Into<String>>(builder_id: B) -> Self {
+        Provenance {
+            builder_id: builder_id.into(),
+            repository: None,
+            commit: None,
+            invocation_id: None,
+        }
+    }
+
+    pub fn source<R: Into<String>, C: Into<String>>(mut self, repository: R, commit: C) -> Self {
+        self.repository = Some(repository.into());
+        self.commit = Some(commit.into());
+        self
+    }
+
+    pub fn invocation_id<I: Into<String>>(mut self, id: I) -> Self {
+        self.invocation_id = Some(id.into());
+        self
+    }
+}
+
+/// An in-toto statement about some files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statement {
+    #[serde(rename = "_type")]
+    pub statement_type: String,
+    pub subject: Vec<Subject>,
+    #[serde(rename = "predicateType")]
+    pub predicate_type: String,
+    pub predicate: serde_json::Value,
+}
+
+/// A file a statement is about, or a file that went into it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subject {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub digest: BTreeMap<String, String>,
+}
+
+impl Subject {
+    fn file(name: &str, sha256: &str) -> Self {
+        Subject {
+            uri: None,
+            name: Some(name.to_string()),
+            digest: BTreeMap::from([("sha256".to_string(), sha256.to_string())]),
+        }
+    }
+
+    /// The SHA-256 recorded for the file, if there is one.
+    pub fn sha256(&self) -> Option<&str> {
+        self.digest.get("sha256").map(String::as_str)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    #[serde(rename = "payloadType")]
+    payload_type: String,
+    payload: String,
+    signatures: Vec<EnvelopeSignature>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnvelopeSignature {
+    keyid: String,
+    sig: String,
+}
+
+/// The statement for a build of `manifest`, written to `files` (each a
+/// name and SHA-256).
+pub(crate) fn statement(
+    provenance: &Provenance,
+    manifest: &Manifest,
+    files: &[(String, String)],
+) -> Statement {
+    let mut dependencies = Vec::new();
+    if let Some(repository) = &provenance.repository {
+        let mut digest = BTreeMap::new();
+        if let Some(commit) = &provenance.commit {
+            digest.insert("gitCommit".to_string(), commit.clone());
+        }
+        dependencies.push(Subject {
+            uri: Some(repository.clone()),
+            name: None,
+            digest,
+        });
+    }
+    // The payload and metadata are the inputs; everything else in the
+    // manifest is generated from them.
+    dependencies.extend(
+        manifest
+            .entries
+            .iter()
+            .filter(|entry| entry.path == METADATA_PATH || Payload::of_path(&entry.path).is_some())
+            .map(|entry| Subject::file(&entry.path, &entry.sha256)),
+    );
+
+    let mut metadata = serde_json::Map::new();
+    if let Some(id) = &provenance.invocation_id {
+        metadata.insert("invocationId".to_string(), id.clone().into());
+    }
+    let predicate = serde_json::json!({
+        "buildDefinition": {
+            "buildType": BUILD_TYPE,
+            "externalParameters": {
+                "formatVersion": manifest.format,
+            },
+            "resolvedDependencies": dependencies,
+        },
+        "runDetails": {
+            "builder": {
+                "id": provenance.builder_id,
+                "version": {
+                    "robot-bundler": env!("CARGO_PKG_VERSION"),
+                },
+            },
+            "metadata": metadata,
+        },
+    });
+    Statement {
+        statement_type: STATEMENT_TYPE.to_string(),
+        subject: files
+            .iter()
+            .map(|(name, sha256)| Subject::file(name, sha256))
+            .collect(),
+        predicate_type: PREDICATE_TYPE.to_string(),
+        predicate,
+    }
+}
+
+/// Sign `statement`, giving a line of an `.intoto.jsonl` file.
+pub(crate) fn sign(statement: &Statement, key: &SigningKey) -> Vec<u8> {
+    let payload = serde_json::to_vec(statement).expect("statement serializes");
+    let signature = key.sign(&pre_auth_encoding(&payload));
+    let envelope = Envelope {
+        payload_type: PAYLOAD_TYPE.to_string(),
+        payload: BASE64.encode(&payload),
+        signatures: vec![EnvelopeSignature {
+            keyid: encode_key(&key.verifying_key()),
+            sig: BASE64.encode(signature.to_bytes()),
+        }],
+    };
+    let mut line = serde_json::to_vec(&envelope).expect("envelope serializes");
+    line.push(b'\n');
+    line
+}
+
+/// Check the attestations in an `.intoto.jsonl` file were signed by one of
+/// `trusted`, returning their statements.
+pub fn verify(data: &[u8], trusted: &[VerifyingKey]) -> Result<Vec<Statement>> {
+    data.split(|&byte| byte == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|line| verify_envelope(line, trusted))
+        .collect()
+}
+
+fn verify_envelope(line: &[u8], trusted: &[VerifyingKey]) -> Result<Statement> {
+    let envelope: Envelope =
+        serde_json::from_slice(line).map_err(|_| BundleError::MalformedSignature)?;
+    if envelope.payload_type != PAYLOAD_TYPE {
+        return Err(BundleError::MalformedSignature);
+    }
+    let payload = BASE64
+        .decode(&envelope.payload)
+        .map_err(|_| BundleError::MalformedSignature)?;
+    let message = pre_auth_encoding(&payload);
+
+    let mut untrusted = None;
+    let mut malformed = false;
+    for signature in &envelope.signatures {
+        // A key which doesn't decode can't be one of `trusted`, and is a
+        // problem with the envelope rather than the caller's keys.
+        let key = match decode_verifying_key(&signature.keyid) {
+            Ok(key) => key,
+            Err(_) => {
+                malformed = true;
+                continue;
+            }
+        };
+        if !trusted.contains(&key) {
+            untrusted = Some(signature.keyid.clone());
+            continue;
+        }
+        let sig = BASE64
+            .decode(&signature.sig)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(BundleError::MalformedSignature)?;
+        key.verify_strict(&message, &sig)
+            .map_err(|_| BundleError::BadSignature)?;
+        return serde_json::from_slice(&payload).map_err(|_| BundleError::MalformedSignature);
+    }
+    Err(match untrusted {
+        Some(key) => BundleError::UntrustedKey(key),
+        None if malformed => BundleError::MalformedSignature,
+        None => BundleError::Unsigned,
+    })
+}
+
+/// DSSE's pre-authentication encoding, which is what actually gets signed.
+fn pre_auth_encoding(payload: &[u8]) -> Vec<u8> {
+    let mut message = format!(
+        "DSSEv1 {} {} {} ",
+        PAYLOAD_TYPE.len(),
+        PAYLOAD_TYPE,
+        payload.len()
+    )
+    .into_bytes();
+    message.extend_from_slice(payload);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, invalid_key, TempDir};
+    use std::fs;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Build a bundle signed by `key(1)` with an attestation, returning
+    /// the attestation.
+    fn attestation(dir: &TempDir) -> Vec<u8> {
+        let path = dir.join("bundle.tar.gz");
+        builder(
+            dir.path(),
+            "[kit]\nname = \"kit\"\n",
+            &[("main.py", "print(1)\n")],
+        )
+        .sign(key(1))
+        .provenance(
+            Provenance::new("https://ci.example/robot")
+                .source("git+https://example/robot", "1a2b3c4"),
+        )
+        .build(&path)
+        .unwrap();
+        fs::read(dir.join(format!("bundle.tar.gz{}", PROVENANCE_SUFFIX))).unwrap()
+    }
+
+    /// Change the envelope in `data`, which holds just one.
+    fn edit_envelope<F: FnOnce(&mut Envelope)>(data: &[u8], change: F) -> Vec<u8> {
+        let mut envelope: Envelope = serde_json::from_slice(data).unwrap();
+        change(&mut envelope);
+        serde_json::to_vec(&envelope).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let data = attestation(&dir);
+        let statements = verify(&data, &[key(1).verifying_key()]).unwrap();
+        assert_eq!(statements.len(), 1);
+        let statement = &statements[0];
+        assert_eq!(statement.predicate_type, PREDICATE_TYPE);
+        assert_eq!(statement.subject[0].name.as_deref(), Some("bundle.tar.gz"));
+        let (_, sha256) = crate::bundle::manifest::hash_reader(
+            fs::File::open(dir.join("bundle.tar.gz")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(statement.subject[0].sha256(), Some(sha256.as_str()));
+        assert_eq!(
+            statement.predicate["buildDefinition"]["resolvedDependencies"][0]["digest"]
+                ["gitCommit"],
+            "1a2b3c4"
+        );
+    }
+
+    #[test]
+    fn rejects_untrusted_keys() {
+        let dir = TempDir::new();
+        let data = attestation(&dir);
+        match verify(&data, &[key(2).verifying_key()]) {
+            Err(BundleError::UntrustedKey(key_hex)) => {
+                assert_eq!(key_hex, encode_key(&key(1).verifying_key()))
+            }
+            other => panic!("expected an untrusted key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let dir = TempDir::new();
+        let data = attestation(&dir);
+        let tampered = edit_envelope(&data, |envelope| {
+            let payload = BASE64.decode(&envelope.payload).unwrap();
+            let payload = String::from_utf8(payload)
+                .unwrap()
+                .replace("1a2b3c4", "5d6e7f8");
+            envelope.payload = BASE64.encode(payload);
+        });
+        assert!(matches!(
+            verify(&tampered, &[key(1).verifying_key()]),
+            Err(BundleError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn invalid_key_is_malformed() {
+        let dir = TempDir::new();
+        let data = attestation(&dir);
+        let forged = edit_envelope(&data, |envelope| {
+            envelope.signatures[0].keyid = invalid_key();
+        });
+        assert!(matches!(
+            verify(&forged, &[key(1).verifying_key()]),
+            Err(BundleError::MalformedSignature)
+        ));
+
+        // It doesn't hide a good signature alongside it.
+        let extra = edit_envelope(&data, |envelope| {
+            envelope.signatures.insert(
+                0,
+                EnvelopeSignature {
+                    keyid: invalid_key(),
+                    sig: String::new(),
+                },
+            );
+        });
+        verify(&extra, &[key(1).verifying_key()]).unwrap();
+    }
+
+    #[test]
+    fn must_be_signed() {
+        let dir = TempDir::new();
+        let path = dir.join("bundle.tar.gz");
+        let result = builder(dir.path(), "[kit]\n", &[])
+            .provenance(Provenance::new("https://ci.example/robot"))
+            .build(&path);
+        assert!(matches!(result, Err(BundleError::UnsignedProvenance)));
+        assert!(!dir
+            .join(format!("bundle.tar.gz{}", PROVENANCE_SUFFIX))
+            .exists());
+    }
+}
@@ -0,0 +1,357 @@
+use super::compression::{self, Codec};
+use super::container::{self, Container};
+use super::encryption::{self, Identity, ENCRYPTED_SUFFIX};
+use super::signing::{ManifestSignature, VerifyingKey};
+use super::split;
+use super::{
+    BundleError, Manifest, Result, FORMAT_VERSION, MANIFEST_PATH, METADATA_PATH,
+    MIN_FORMAT_VERSION, OPENPGP_SIGNATURE_PATH, SIGNATURE_PATH,
+};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// The most any of the entries at the front of an archive may hold, so a
+/// hostile bundle can't exhaust memory before its signature is checked.
+pub(crate) const HEAD_ENTRY_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// The entries at the front of every bundle archive, before the payload.
+#[derive(Debug)]
+pub(crate) struct Head {
+    pub(crate) metadata: String,
+    pub(crate) manifest: Vec<u8>,
+    pub(crate) signature: Option<Vec<u8>>,
+    pub(crate) openpgp_signature: Option<Vec<u8>>,
+}
+
+/// What [`Bundle::peek`] reads from a bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleInfo {
+    pub container: Container,
+    /// See [`Bundle::codec`].
+    pub codec: Codec,
+    pub encrypted: bool,
+    pub metadata: toml::Table,
+    pub manifest: Manifest,
+    pub signed: bool,
+}
+
+/// A bundle archive on disk.
+#[derive(Clone)]
+pub struct Bundle {
+    path: PathBuf,
+    identities: Vec<Identity>,
+}
+
+impl fmt::Debug for Bundle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bundle")
+            .field("path", &self.path)
+            .field("identities", &self.identities.len())
+            .finish()
+    }
+}
+
+impl Bundle {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        File::open(&path)?;
+        Ok(Bundle {
+            path,
+            identities: Vec::new(),
+        })
+    }
+
+    /// Use `identities` to decrypt the archive, if it is encrypted, and
+    /// any encrypted entries read with [`Bundle::decrypt_entry`].
+    pub fn with_identities(mut self, identities: Vec<Identity>) -> Self {
+        self.identities = identities;
+        self
+    }
+
+    /// Read a bundle's metadata and manifest without touching its payload.
+    ///
+    /// These lead the archive, so only its first few kilobytes are ever
+    /// decompressed, however large the bundle is.
+    pub fn peek<P: AsRef<Path>>(path: P) -> Result<BundleInfo> {
+        Bundle::open(path)?.info()
+    }
+
+    /// Read just the bundle TOML from a bundle, tarball or zip, as a
+    /// `T`, for tools which have their own type for the metadata and
+    /// don't care about the payload.
+    ///
+    /// Only the head of the archive is read, as for [`Bundle::peek`].
+    pub fn load_metadata<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T> {
+        Ok(toml::from_str(&Bundle::open(path)?.metadata()?)?)
+    }
+
+    /// As [`Bundle::peek`], for an already opened (and possibly
+    /// encrypted) bundle.
+    pub fn info(&self) -> Result<BundleInfo> {
+        let head = self.head()?;
+        Ok(BundleInfo {
+            container: self.container()?,
+            codec: self.codec()?,
+            encrypted: self.is_encrypted()?,
+            metadata: head.metadata.parse()?,
+            manifest: parse_manifest(&head.manifest)?,
+            signed: head.signature.is_some() || head.openpgp_signature.is_some(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the archive as a whole is encrypted.
+    pub fn is_encrypted(&self) -> Result<bool> {
+        let mut reader = BufReader::new(split::open(&self.path)?);
+        Ok(encryption::is_encrypted(reader.fill_buf()?))
+    }
+
+    /// Whether the bundle is a tarball or a zip file.
+    pub fn container(&self) -> Result<Container> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        Ok(Container::from_magic(reader.fill_buf()?))
+    }
+
+    /// The codec the archive is compressed with. Zip bundles are deflated,
+    /// like gzip, so are reported as [`Codec::Gzip`].
+    pub fn codec(&self) -> Result<Codec> {
+        match self.container()? {
+            Container::Tar => compression::detect(self.raw()?),
+            Container::Zip => Ok(Codec::Gzip),
+        }
+    }
+
+    /// The compressed archive, joined back together and decrypted if need
+    /// be.
+    fn raw(&self) -> Result<Box<dyn Read>> {
+        let mut reader = BufReader::new(split::open(&self.path)?);
+        if encryption::is_encrypted(reader.fill_buf()?) {
+            Ok(Box::new(encryption::decrypt_reader(
+                reader,
+                &self.identities,
+            )?))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    pub(crate) fn archive(&self) -> Result<tar::Archive<Box<dyn Read>>> {
+        match self.container()? {
+            Container::Tar => Ok(tar::Archive::new(compression::decoder(self.raw()?)?)),
+            Container::Zip => Ok(tar::Archive::new(container::zip_as_tar(&self.path)?)),
+        }
+    }
+
+    /// Read and decrypt the encrypted entry stored for `path`, which is
+    /// given without its `.age` suffix.
+    pub fn decrypt_entry(&self, path: &str) -> Result<Vec<u8>> {
+        let stored = format!("{}{}", path, ENCRYPTED_SUFFIX);
+        let mut archive = self.archive()?;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.to_str() == Some(stored.as_str()) {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                return encryption::decrypt(&data, &self.identities);
+            }
+        }
+        Err(BundleError::NoSuchEntry(stored))
+    }
+
+    /// Read the metadata, manifest and signature, stopping at the first
+    /// payload entry.
+    pub(crate) fn head(&self) -> Result<Head> {
+        let mut archive = self.archive()?;
+        let mut metadata = None;
+        let mut manifest = None;
+        let mut signature = None;
+        let mut openpgp_signature = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            match path.as_str() {
+                METADATA_PATH => {
+                    let data = read_head_entry(&mut entry, &path)?;
+                    metadata = Some(
+                        String::from_utf8(data).map_err(|_| BundleError::NotUtf8(METADATA_PATH))?,
+                    );
+                }
+                MANIFEST_PATH => manifest = Some(read_head_entry(&mut entry, &path)?),
+                SIGNATURE_PATH => signature = Some(read_head_entry(&mut entry, &path)?),
+                OPENPGP_SIGNATURE_PATH => {
+                    openpgp_signature = Some(read_head_entry(&mut entry, &path)?)
+                }
+                _ => break,
+            }
+        }
+        Ok(Head {
+            metadata: metadata.ok_or(BundleError::MissingEntry(METADATA_PATH))?,
+            manifest: manifest.ok_or(BundleError::MissingEntry(MANIFEST_PATH))?,
+            signature,
+            openpgp_signature,
+        })
+    }
+
+    /// The bundle TOML, as stored in the archive.
+    pub fn metadata(&self) -> Result<String> {
+        Ok(self.head()?.metadata)
+    }
+
+    pub fn manifest(&self) -> Result<Manifest> {
+        parse_manifest(&self.head()?.manifest)
+    }
+
+    /// Check the manifest is signed by one of `trusted`.
+    pub fn verify_signature(&self, trusted: &[VerifyingKey]) -> Result<()> {
+        let head = self.head()?;
+        let signature = head.signature.ok_or(BundleError::Unsigned)?;
+        ManifestSignature::from_bytes(&signature)?.verify(&head.manifest, trusted)
+    }
+
+    /// Check the manifest carries an OpenPGP signature by one of `trusted`.
+    #[cfg(feature = "openpgp")]
+    pub fn verify_openpgp_signature(&self, trusted: &[super::openpgp::Cert]) -> Result<()> {
+        let head = self.head()?;
+        let signature = head.openpgp_signature.ok_or(BundleError::Unsigned)?;
+        super::openpgp::verify_detached(&signature, &head.manifest, trusted).map(|_| ())
+    }
+}
+
+/// Read one of the entries at the front of an archive, refusing any over
+/// [`HEAD_ENTRY_LIMIT`].
+pub(crate) fn read_head_entry<R: Read>(entry: &mut tar::Entry<R>, path: &str) -> Result<Vec<u8>> {
+    let size = entry.header().size()?;
+    if size > HEAD_ENTRY_LIMIT {
+        return Err(BundleError::EntryTooLarge {
+            path: path.to_string(),
+            size,
+            limit: HEAD_ENTRY_LIMIT,
+        });
+    }
+    let mut data = Vec::new();
+    entry.take(HEAD_ENTRY_LIMIT).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub(crate) fn parse_manifest(bytes: &[u8]) -> Result<Manifest> {
+    let text = std::str::from_utf8(bytes).map_err(|_| BundleError::NotUtf8(MANIFEST_PATH))?;
+    check_format(text)?;
+    text.parse().map_err(BundleError::Manifest)
+}
+
+/// Refuse manifests in a format this version can't read, before trying to
+/// make sense of the rest of them.
+fn check_format(text: &str) -> Result<()> {
+    let table: toml::Table = text.parse().map_err(BundleError::Manifest)?;
+    // A missing or mistyped version is left for the full parse to report.
+    if let Some(format) = table.get("format").and_then(toml::Value::as_integer) {
+        if format > i64::from(FORMAT_VERSION) {
+            return Err(BundleError::NewerFormat(format));
+        }
+        if format < i64::from(MIN_FORMAT_VERSION) {
+            return Err(BundleError::UnsupportedFormat(format));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+    use flate2::write::GzEncoder;
+
+    /// Write a gzipped tarball of `entries` to `dir/name`.
+    fn tarball(dir: &TempDir, name: &str, entries: &[(&str, &[u8])]) -> PathBuf {
+        let path = dir.join(name);
+        let encoder = GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (entry, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, entry, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn refuses_oversized_head_entries() {
+        let dir = TempDir::new();
+        let huge = vec![b' '; HEAD_ENTRY_LIMIT as usize + 1];
+        for entry in [METADATA_PATH, MANIFEST_PATH, SIGNATURE_PATH] {
+            let mut entries = vec![(METADATA_PATH, &b"[kit]\n"[..]), (MANIFEST_PATH, b"")];
+            entries.retain(|(path, _)| *path != entry);
+            entries.push((entry, &huge));
+            let path = tarball(&dir, "huge.tar.gz", &entries);
+            match Bundle::peek(&path) {
+                Err(BundleError::EntryTooLarge { path, size, limit }) => {
+                    assert_eq!(path, entry);
+                    assert_eq!(size, HEAD_ENTRY_LIMIT + 1);
+                    assert_eq!(limit, HEAD_ENTRY_LIMIT);
+                }
+                other => panic!("expected {} to be too large, got {:?}", entry, other),
+            }
+        }
+    }
+
+    /// Peek at a bundle whose manifest is `manifest`.
+    fn peek_manifest(manifest: &str) -> Result<Manifest> {
+        let dir = TempDir::new();
+        let entries = [
+            (METADATA_PATH, &b"[kit]\n"[..]),
+            (MANIFEST_PATH, manifest.as_bytes()),
+        ];
+        Bundle::peek(tarball(&dir, "bundle.tar.gz", &entries)).map(|info| info.manifest)
+    }
+
+    #[test]
+    fn refuses_newer_formats() {
+        let newer = i64::from(FORMAT_VERSION) + 1;
+        match peek_manifest(&format!("format = {}\n", newer)) {
+            Err(BundleError::NewerFormat(format)) => assert_eq!(format, newer),
+            other => panic!("expected a newer format, got {:?}", other),
+        }
+        // Even when the rest of the manifest makes no sense to this
+        // version.
+        assert!(matches!(
+            peek_manifest(&format!("format = {}\nfiles = 3\n", newer)),
+            Err(BundleError::NewerFormat(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_unsupported_formats() {
+        let older = i64::from(MIN_FORMAT_VERSION) - 1;
+        match peek_manifest(&format!("format = {}\n", older)) {
+            Err(BundleError::UnsupportedFormat(format)) => assert_eq!(format, older),
+            other => panic!("expected an unsupported format, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unversioned_manifests_are_version_1() {
+        let manifest =
+            peek_manifest("[[files]]\npath = \"bundle.toml\"\nsize = 6\nsha256 = \"00\"\n")
+                .unwrap();
+        assert_eq!(manifest.format, 1);
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(
+            peek_manifest(&format!("format = {}\n", FORMAT_VERSION))
+                .unwrap()
+                .format,
+            FORMAT_VERSION
+        );
+        assert!(matches!(
+            peek_manifest("format = \"2\"\n"),
+            Err(BundleError::Manifest(_))
+        ));
+    }
+}
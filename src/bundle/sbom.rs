@@ -0,0 +1,234 @@
+//! Software bill of materials for bundles, as an SPDX 2.3 tag-value
+//! document stored in the archive.
+//!
+//! The SBOM lists each component with its version, each firmware blob
+//! with its SHA-256, and any other packages the builder is told about,
+//! such as kit software or OS packages.
+
+use super::{kit_field, ManifestEntry, Payload};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Path of the SBOM inside the archive.
+pub const SBOM_PATH: &str = "sbom.spdx";
+
+/// A package to list in the SBOM which the builder can't see for itself.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SbomPackage {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    /// SPDX licence expression, such as `MIT OR Apache-2.0`.
+    #[serde(default)]
+    pub license: Option<String>,
+}
+
+impl SbomPackage {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        SbomPackage {
+            name: name.into(),
+            version: None,
+            license: None,
+        }
+    }
+
+    pub fn version<V: Into<String>>(mut self, version: V) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn license<L: Into<String>>(mut self, license: L) -> Self {
+        self.license = Some(license.into());
+        self
+    }
+}
+
+/// What the SBOM is generated from.
+pub(crate) struct SbomInput<'a> {
+    pub(crate) metadata: &'a toml::Table,
+    pub(crate) payload: &'a [ManifestEntry],
+    pub(crate) components: &'a [Payload],
+    pub(crate) versions: &'a BTreeMap<Payload, String>,
+    pub(crate) packages: &'a [SbomPackage],
+}
+
+/// Write the SBOM.
+///
+/// The document is named after the bundle's `kit.name` and `kit.version`
+/// metadata, if it has them. Its creation time is taken from
+/// `SOURCE_DATE_EPOCH` if that is set, so reproducible builds stay
+/// reproducible.
+pub(crate) fn generate(input: &SbomInput) -> String {
+    let name = match (
+        kit_field(input.metadata, "name"),
+        kit_field(input.metadata, "version"),
+    ) {
+        (Some(name), Some(version)) => format!("{}-{}", name, version),
+        (Some(name), None) => name.to_string(),
+        _ => "bundle".to_string(),
+    };
+    let mut namespace = Sha256::new();
+    namespace.update(name.as_bytes());
+    for entry in input.payload {
+        namespace.update(entry.sha256.as_bytes());
+    }
+
+    let mut doc = String::new();
+    line(&mut doc, "SPDXVersion", "SPDX-2.3");
+    line(&mut doc, "DataLicense", "CC0-1.0");
+    line(&mut doc, "SPDXID", "SPDXRef-DOCUMENT");
+    line(&mut doc, "DocumentName", &name);
+    line(
+        &mut doc,
+        "DocumentNamespace",
+        &format!(
+            "urn:robot-bundler:sbom:{}",
+            hex::encode(namespace.finalize())
+        ),
+    );
+    line(
+        &mut doc,
+        "Creator",
+        &format!("Tool: robot-bundler-{}", env!("CARGO_PKG_VERSION")),
+    );
+    line(&mut doc, "Created", &timestamp(creation_time()));
+
+    for &payload in input.components {
+        let id = format!("SPDXRef-Component-{}", payload.directory());
+        package(
+            &mut doc,
+            &id,
+            payload.directory(),
+            input.versions.get(&payload).map(String::as_str),
+            None,
+        );
+        relationship(&mut doc, "SPDXRef-DOCUMENT", "DESCRIBES", &id);
+    }
+
+    let firmware = input
+        .payload
+        .iter()
+        .filter(|entry| Payload::of_path(&entry.path) == Some(Payload::Firmware));
+    for (index, entry) in firmware.enumerate() {
+        let id = format!("SPDXRef-Firmware-{}", index);
+        package(&mut doc, &id, &entry.path, None, None);
+        line(
+            &mut doc,
+            "PackageChecksum",
+            &format!("SHA256: {}", entry.sha256),
+        );
+        relationship(&mut doc, "SPDXRef-Component-firmware", "CONTAINS", &id);
+    }
+
+    for (index, extra) in input.packages.iter().enumerate() {
+        let id = format!("SPDXRef-Package-{}", index);
+        package(
+            &mut doc,
+            &id,
+            &extra.name,
+            extra.version.as_deref(),
+            extra.license.as_deref(),
+        );
+        relationship(&mut doc, "SPDXRef-DOCUMENT", "DESCRIBES", &id);
+    }
+    doc
+}
+
+fn line(doc: &mut String, tag: &str, value: &str) {
+    // Tag-value fields are a single line; anything else needs <text>.
+    let value = value.replace(['\r', '\n'], " ");
+    writeln!(doc, "{}: {}", tag, value).expect("writing to a string");
+}
+
+fn package(doc: &mut String, id: &str, name: &str, version: Option<&str>, license: Option<&str>) {
+    doc.push('\n');
+    line(doc, "PackageName", name);
+    line(doc, "SPDXID", id);
+    if let Some(version) = version {
+        line(doc, "PackageVersion", version);
+    }
+    line(doc, "PackageDownloadLocation", "NOASSERTION");
+    line(doc, "FilesAnalyzed", "false");
+    line(
+        doc,
+        "PackageLicenseDeclared",
+        license.unwrap_or("NOASSERTION"),
+    );
+    line(doc, "PackageCopyrightText", "NOASSERTION");
+}
+
+fn relationship(doc: &mut String, from: &str, kind: &str, to: &str) {
+    line(doc, "Relationship", &format!("{} {} {}", from, kind, to));
+}
+
+fn creation_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        })
+}
+
+/// Format seconds since the epoch as an RFC 3339 UTC timestamp, such as
+/// `2024-03-01T12:00:00Z`, as used by SPDX and TUF.
+pub(crate) fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document_name(metadata: &str) -> String {
+        let metadata: toml::Table = metadata.parse().unwrap();
+        let sbom = generate(&SbomInput {
+            metadata: &metadata,
+            payload: &[],
+            components: &[],
+            versions: &BTreeMap::new(),
+            packages: &[],
+        });
+        sbom.lines()
+            .find_map(|line| line.strip_prefix("DocumentName: "))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn named_after_kit() {
+        assert_eq!(
+            document_name("[kit]\nname = \"kit\"\nversion = \"1.2.3\"\n"),
+            "kit-1.2.3"
+        );
+        assert_eq!(document_name("[kit]\nname = \"kit\"\n"), "kit");
+        assert_eq!(document_name("name = \"kit\"\n"), "bundle");
+    }
+}
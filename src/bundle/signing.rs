@@ -0,0 +1,169 @@
+//! ed25519 signatures over bundle manifests.
+//!
+//! Sigstore keyless signing isn't built in, as it needs a Fulcio and Rekor
+//! client. CI can still sign with its OIDC identity, without a long-lived
+//! key, by running `cosign` from a post-build hook:
+//!
+//! ```toml
+//! [hooks]
+//! post_build = [["sh", "-c", "cosign sign-blob --yes --bundle \"$BUNDLE_OUTPUT.sigstore.json\" \"$BUNDLE_OUTPUT\""]]
+//! ```
+//!
+//! The signature and its transparency log entry are then checked with
+//! `cosign verify-blob --bundle bundle.tar.gz.sigstore.json
+//! --certificate-identity <workflow> --certificate-oidc-issuer <issuer>
+//! bundle.tar.gz`.
+
+use super::{BundleError, Result};
+use ed25519_dalek::{Signature, Signer};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFile {
+    key: String,
+    signature: String,
+}
+
+/// A detached ed25519 signature over a bundle's manifest, along with the
+/// public half of the key which made it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestSignature {
+    key: VerifyingKey,
+    signature: Signature,
+}
+
+impl ManifestSignature {
+    pub fn sign(key: &SigningKey, manifest: &[u8]) -> Self {
+        ManifestSignature {
+            key: key.verifying_key(),
+            signature: key.sign(manifest),
+        }
+    }
+
+    pub fn key(&self) -> &VerifyingKey {
+        &self.key
+    }
+
+    /// Check the signature was made over `manifest` by one of `trusted`.
+    pub fn verify(&self, manifest: &[u8], trusted: &[VerifyingKey]) -> Result<()> {
+        if !trusted.contains(&self.key) {
+            return Err(BundleError::UntrustedKey(encode_key(&self.key)));
+        }
+        self.key
+            .verify_strict(manifest, &self.signature)
+            .map_err(|_| BundleError::BadSignature)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let file = SignatureFile {
+            key: encode_key(&self.key),
+            signature: hex::encode(self.signature.to_bytes()),
+        };
+        toml::to_string(&file)
+            .expect("signature file always serializes")
+            .into_bytes()
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let file: SignatureFile = std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|text| toml::from_str(text).ok())
+            .ok_or(BundleError::MalformedSignature)?;
+        let signature = decode_hex(&file.signature)
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(BundleError::MalformedSignature)?;
+        // A key which isn't a valid ed25519 point is as malformed as a bad
+        // signature, not a problem with the caller's keys.
+        let key = decode_verifying_key(&file.key).map_err(|_| BundleError::MalformedSignature)?;
+        Ok(ManifestSignature { key, signature })
+    }
+}
+
+/// Hex-encode a public key, as used in signature files and key files.
+pub fn encode_key(key: &VerifyingKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+pub fn decode_verifying_key(text: &str) -> Result<VerifyingKey> {
+    decode_hex(text)
+        .and_then(|bytes| bytes.try_into().ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| BundleError::InvalidKey(text.trim().to_string()))
+}
+
+pub fn decode_signing_key(text: &str) -> Result<SigningKey> {
+    decode_hex(text)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|bytes| SigningKey::from_bytes(&bytes))
+        .ok_or_else(|| BundleError::InvalidKey(String::from("<signing key>")))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    hex::decode(text.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::invalid_key;
+
+    const MANIFEST: &[u8] = b"format = 2\n";
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn round_trip() {
+        let signature = ManifestSignature::sign(&key(1), MANIFEST);
+        let parsed = ManifestSignature::from_bytes(&signature.to_bytes()).unwrap();
+        assert_eq!(parsed, signature);
+        parsed.verify(MANIFEST, &[key(1).verifying_key()]).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_manifests() {
+        let signature = ManifestSignature::sign(&key(1), MANIFEST);
+        assert!(matches!(
+            signature.verify(b"format = 1\n", &[key(1).verifying_key()]),
+            Err(BundleError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_untrusted_keys() {
+        let signature = ManifestSignature::sign(&key(1), MANIFEST);
+        match signature.verify(MANIFEST, &[key(2).verifying_key()]) {
+            Err(BundleError::UntrustedKey(key_hex)) => {
+                assert_eq!(key_hex, encode_key(&key(1).verifying_key()))
+            }
+            other => panic!("expected an untrusted key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_key_is_malformed() {
+        let signature = ManifestSignature::sign(&key(1), MANIFEST);
+        let file = format!(
+            "key = \"{}\"\nsignature = \"{}\"\n",
+            invalid_key(),
+            hex::encode(signature.signature.to_bytes())
+        );
+        assert!(matches!(
+            ManifestSignature::from_bytes(file.as_bytes()),
+            Err(BundleError::MalformedSignature)
+        ));
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let key = key(3).verifying_key();
+        assert_eq!(decode_verifying_key(&encode_key(&key)).unwrap(), key);
+        assert!(decode_verifying_key("not hex").is_err());
+        assert!(decode_verifying_key(&invalid_key()).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+//! Bundle archives split into parts, for media such as FAT32 USB sticks
+//! which can't hold large files.
+//!
+//! A split bundle is written as numbered part files (`bundle.tar.gz.000`,
+//! `bundle.tar.gz.001`, ...) which are the archive cut into pieces, and a
+//! small index at the bundle's own path listing them in order. Opening the
+//! index with [`Bundle::open`](super::Bundle::open) reads the parts back
+//! as one archive.
+
+use super::manifest::HashingReader;
+use super::{BundleError, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The largest file FAT32 can hold.
+pub const FAT32_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+
+/// The first line of every split index, which is how one is told apart
+/// from an archive.
+const SPLIT_MAGIC: &[u8] = b"# robot-bundler split archive\n";
+
+/// One part of a split archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Part {
+    /// File name of the part, in the same directory as the index.
+    pub path: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the part.
+    pub sha256: String,
+}
+
+/// The index of a split archive, listing its parts in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitIndex {
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+impl SplitIndex {
+    /// Read the index at `path`, or `None` if it holds an unsplit archive.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let mut file = File::open(path)?;
+        let mut magic = Vec::with_capacity(SPLIT_MAGIC.len());
+        (&mut file)
+            .take(SPLIT_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic != SPLIT_MAGIC {
+            return Ok(None);
+        }
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        toml::from_str(&text).map(Some).map_err(BundleError::Split)
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string(self).expect("split index serializes");
+        let mut file = File::create(path)?;
+        file.write_all(SPLIT_MAGIC)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.parts.iter().map(|part| part.size).sum()
+    }
+}
+
+/// Open the archive at `path`, joining its parts if it is split.
+pub(crate) fn open(path: &Path) -> Result<Box<dyn Read>> {
+    match SplitIndex::read(path)? {
+        None => Ok(Box::new(File::open(path)?)),
+        Some(index) => {
+            let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            Ok(Box::new(JoinedReader {
+                dir,
+                parts: index.parts.into_iter(),
+                current: None,
+            }))
+        }
+    }
+}
+
+/// Reads the parts of a split archive one after another, checking each
+/// is the size and hash the index says once it has been read.
+struct JoinedReader {
+    dir: PathBuf,
+    parts: std::vec::IntoIter<Part>,
+    current: Option<(Part, HashingReader<BufReader<File>>)>,
+}
+
+impl Read for JoinedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                let part = match self.parts.next() {
+                    Some(part) => part,
+                    None => return Ok(0),
+                };
+                if Path::new(&part.path).file_name() != Some(part.path.as_ref()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("split archive part {} is not a file name", part.path),
+                    ));
+                }
+                let file = File::open(self.dir.join(&part.path))?;
+                self.current = Some((part, HashingReader::new(BufReader::new(file))));
+            }
+            let (_, reader) = self.current.as_mut().expect("a part is open");
+            let n = reader.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let (part, reader) = self.current.take().expect("a part is open");
+            if reader.finish() != (part.size, part.sha256.clone()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("split archive part {} is corrupt", part.path),
+                ));
+            }
+        }
+    }
+}
+
+/// Writes an archive as numbered parts of at most `part_size` bytes each
+/// next to `output`, then the index at `output` itself.
+pub(crate) struct SplitWriter {
+    output: PathBuf,
+    part_size: u64,
+    current: Option<OpenPart>,
+    parts: Vec<Part>,
+}
+
+struct OpenPart {
+    file: BufWriter<File>,
+    path: String,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl SplitWriter {
+    pub(crate) fn new(output: &Path, part_size: u64) -> Self {
+        SplitWriter {
+            output: output.to_path_buf(),
+            part_size,
+            current: None,
+            parts: Vec::new(),
+        }
+    }
+
+    fn part_name(&self, index: usize) -> String {
+        let name = self
+            .output
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("{}.{:03}", name, index)
+    }
+
+    fn close_part(&mut self) -> io::Result<()> {
+        if let Some(part) = self.current.take() {
+            part.file
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            self.parts.push(Part {
+                path: part.path,
+                size: part.size,
+                sha256: hex::encode(part.hasher.finalize()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Paths of every part written so far, including one still open.
+    pub(crate) fn part_paths(&self) -> Vec<PathBuf> {
+        let count = self.parts.len() + self.current.iter().count();
+        (0..count)
+            .map(|index| self.output.with_file_name(self.part_name(index)))
+            .collect()
+    }
+
+    /// Close the last part and write the index.
+    pub(crate) fn finish(mut self) -> Result<SplitIndex> {
+        self.close_part()?;
+        let index = SplitIndex {
+            parts: std::mem::take(&mut self.parts),
+        };
+        index.write(&self.output)?;
+        Ok(index)
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|part| part.size == self.part_size)
+        {
+            self.close_part()?;
+        }
+        if self.current.is_none() {
+            let path = self.part_name(self.parts.len());
+            let file = File::create(self.output.with_file_name(&path))?;
+            self.current = Some(OpenPart {
+                file: BufWriter::new(file),
+                path,
+                hasher: Sha256::new(),
+                size: 0,
+            });
+        }
+        let part = self.current.as_mut().expect("a part is open");
+        let room = (self.part_size - part.size).min(buf.len() as u64) as usize;
+        let n = part.file.write(&buf[..room])?;
+        part.hasher.update(&buf[..n]);
+        part.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(part) => part.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Remove a split archive's index and parts, ignoring any already gone.
+pub(crate) fn remove(output: &Path, parts: &[PathBuf]) {
+    let _ = fs::remove_file(output);
+    for part in parts {
+        let _ = fs::remove_file(part);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::{builder, TempDir};
+    use crate::bundle::{Bundle, Verification};
+
+    /// Bytes which differ from one part to the next.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Write `data` split into parts of `part_size` bytes at `dir/name`.
+    fn write_split(dir: &TempDir, name: &str, data: &[u8], part_size: u64) -> SplitIndex {
+        let mut writer = SplitWriter::new(&dir.join(name), part_size);
+        // Small, uneven writes, so some straddle the part boundaries.
+        for chunk in data.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_joined(path: &Path) -> io::Result<Vec<u8>> {
+        let mut joined = Vec::new();
+        open(path).unwrap().read_to_end(&mut joined)?;
+        Ok(joined)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let data = data(1000);
+        let index = write_split(&dir, "archive", &data, 64);
+        assert_eq!(index.parts.len(), 16);
+        assert_eq!(index.parts[0].path, "archive.000");
+        assert!(index.parts.iter().all(|part| part.size <= 64));
+        assert_eq!(index.total_size(), 1000);
+        assert_eq!(SplitIndex::read(dir.join("archive")).unwrap(), Some(index));
+        assert_eq!(read_joined(&dir.join("archive")).unwrap(), data);
+    }
+
+    #[test]
+    fn splits_bundles() {
+        let dir = TempDir::new();
+        // Hashes, so there is too much to fit one part once compressed.
+        let contents: String = (0..100u32)
+            .map(|i| hex::encode(Sha256::digest(i.to_le_bytes())))
+            .collect();
+        let files = [("main.py", contents.as_str())];
+        let path = dir.join("bundle.tar.gz");
+        builder(dir.path(), "[kit]\n", &files)
+            .split(512)
+            .build(&path)
+            .unwrap();
+        assert!(dir.join("bundle.tar.gz.001").exists());
+        let bundle = Bundle::open(&path).unwrap();
+        assert_eq!(bundle.verify(&[]).unwrap(), Verification::Unsigned);
+    }
+
+    #[test]
+    fn unsplit_archives_have_no_index() {
+        let dir = TempDir::new();
+        fs::write(dir.join("archive"), data(100)).unwrap();
+        assert_eq!(SplitIndex::read(dir.join("archive")).unwrap(), None);
+        assert_eq!(read_joined(&dir.join("archive")).unwrap(), data(100));
+    }
+
+    #[test]
+    fn rejects_corrupt_parts() {
+        let dir = TempDir::new();
+        write_split(&dir, "archive", &data(200), 64);
+        let part = dir.join("archive.001");
+        let mut corrupt = fs::read(&part).unwrap();
+        corrupt[0] ^= 1;
+        fs::write(&part, corrupt).unwrap();
+
+        let error = read_joined(&dir.join("archive")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("archive.001"));
+    }
+
+    #[test]
+    fn rejects_missing_parts() {
+        let dir = TempDir::new();
+        write_split(&dir, "archive", &data(200), 64);
+        fs::remove_file(dir.join("archive.002")).unwrap();
+        let error = read_joined(&dir.join("archive")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rejects_parts_outside_the_directory() {
+        let dir = TempDir::new();
+        let index = SplitIndex {
+            parts: vec![Part {
+                path: "../archive.000".to_string(),
+                size: 0,
+                sha256: String::new(),
+            }],
+        };
+        index.write(&dir.join("archive")).unwrap();
+        let error = read_joined(&dir.join("archive")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_indexes() {
+        let dir = TempDir::new();
+        let mut text = SPLIT_MAGIC.to_vec();
+        text.extend_from_slice(b"[[parts]]\npath = \"archive.000\"\n");
+        fs::write(dir.join("archive"), text).unwrap();
+        assert!(matches!(
+            SplitIndex::read(dir.join("archive")),
+            Err(BundleError::Split(_))
+        ));
+        assert!(matches!(
+            Bundle::open(dir.join("archive")).unwrap().is_encrypted(),
+            Err(BundleError::Split(_))
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+//! Custom build steps, for work a bundle needs doing to it as it is built,
+//! such as compiling usercode or injecting marker files.
+//!
+//! Steps are registered with [`BundleBuilder::step`](super::BundleBuilder::step)
+//! and run in the order they were registered, each time the archive is
+//! written. Every build gets a fresh staging directory, laid out like the
+//! archive: files a step writes below a payload directory in it, such as
+//! `usercode/main.pyc`, are added to the bundle. Anything else in it is
+//! scratch space. The directory is removed once the archive is written.
+
+use super::{BundleError, Payload, Result};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A step run while building a bundle.
+pub trait BundleStep: Send + Sync {
+    /// The name of the step, used in error messages.
+    fn name(&self) -> &str;
+
+    /// Do the step's work. An error fails the build.
+    fn run(&self, context: &StepContext) -> Result<()>;
+}
+
+/// What a step can see of the build.
+pub struct StepContext<'a> {
+    pub(crate) staging: &'a Path,
+    pub(crate) metadata: &'a toml::Table,
+    pub(crate) files: Vec<(&'a str, Option<&'a Path>)>,
+}
+
+impl StepContext<'_> {
+    /// The staging directory for this build.
+    pub fn staging_dir(&self) -> &Path {
+        self.staging
+    }
+
+    /// Where in the staging directory files for `payload` go.
+    pub fn payload_dir(&self, payload: Payload) -> PathBuf {
+        self.staging.join(payload.directory())
+    }
+
+    /// The parsed bundle TOML.
+    pub fn metadata(&self) -> &toml::Table {
+        self.metadata
+    }
+
+    /// The archive paths of the files added to the builder, in order.
+    /// Files staged by steps are not included.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|&(path, _)| path)
+    }
+
+    /// The file on disk an archive path comes from, if it comes from one
+    /// rather than a reader or memory.
+    pub fn source(&self, path: &str) -> Option<&Path> {
+        self.files
+            .iter()
+            .find(|&&(candidate, _)| candidate == path)
+            .and_then(|&(_, source)| source)
+    }
+}
+
+/// The steps registered with a builder.
+#[derive(Default)]
+pub(crate) struct Steps(pub(crate) Vec<Box<dyn BundleStep>>);
+
+impl Steps {
+    pub(crate) fn run(&self, context: &StepContext) -> Result<()> {
+        self.0.iter().try_for_each(|step| {
+            step.run(context)
+                .map_err(|e| BundleError::Step(step.name().to_string(), Box::new(e)))
+        })
+    }
+}
+
+impl fmt::Debug for Steps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|step| step.name()))
+            .finish()
+    }
+}
+
+/// A staging directory, removed when dropped.
+pub(crate) struct Staging {
+    path: PathBuf,
+}
+
+impl Staging {
+    pub(crate) fn create() -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "robot-bundler-staging-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)?;
+        Ok(Staging { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,69 @@
+use super::Result;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A local content-addressed store of compressed archive entries, shared
+/// between builds so payloads which haven't changed aren't compressed
+/// again.
+///
+/// Blobs are keyed by a hash of everything that goes into them, so the
+/// store never needs invalidating, and can be deleted at any time. It is
+/// safe for several builds to share a store at once.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    /// Open the store at `root`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(BlobStore { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(&key[2..])
+    }
+
+    /// The blob stored under `key`, if there is one.
+    pub(crate) fn get(&self, key: &str) -> io::Result<Option<File>> {
+        match File::open(self.blob_path(key)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a temporary file to write a new blob to, before it is
+    /// moved into place with [`BlobStore::commit`].
+    pub(crate) fn create_temp(&self) -> io::Result<(PathBuf, File)> {
+        let name = format!(
+            "tmp-{}-{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.root.join(name);
+        let file = File::create(&path)?;
+        Ok((path, file))
+    }
+
+    /// Move a finished temporary file into place as the blob for `key`.
+    ///
+    /// Renaming is atomic, so concurrent builds storing the same blob
+    /// just replace one complete copy with another.
+    pub(crate) fn commit(&self, temp: &Path, key: &str) -> io::Result<()> {
+        let path = self.blob_path(key);
+        fs::create_dir_all(path.parent().expect("blob paths have a parent"))?;
+        fs::rename(temp, path)
+    }
+}
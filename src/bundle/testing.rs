@@ -0,0 +1,93 @@
+//! Helpers shared by the unit tests.
+
+use super::signing::VerifyingKey;
+use super::{BundleBuilder, Payload};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh directory under the system temp directory, removed on drop.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "robot-bundler-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("creating test directory");
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A builder for a bundle with `metadata` as its TOML and `files` as
+/// usercode, all written under `dir`.
+pub(crate) fn builder(dir: &Path, metadata: &str, files: &[(&str, &str)]) -> BundleBuilder {
+    let metadata_path = dir.join("bundle.toml");
+    fs::write(&metadata_path, metadata).expect("writing test metadata");
+    let mut builder = BundleBuilder::new(&metadata_path);
+    for (index, (path, contents)) in files.iter().enumerate() {
+        let source = dir.join(format!("source-{}", index));
+        fs::write(&source, contents).expect("writing test file");
+        builder
+            .add_file(Payload::Usercode, &source, path)
+            .expect("adding test file");
+    }
+    builder
+}
+
+/// Copy the gzipped tarball at `source` to `output`, passing each entry's
+/// path and contents through `change`, to make tampered bundles.
+pub(crate) fn rewrite_tarball<F>(source: &Path, output: &Path, mut change: F)
+where
+    F: FnMut(&str, &mut Vec<u8>),
+{
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(source).unwrap()));
+    let encoder = GzEncoder::new(File::create(output).unwrap(), Default::default());
+    let mut builder = tar::Builder::new(encoder);
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut header = entry.header().clone();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        change(&path, &mut data);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data.as_slice()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+}
+
+/// Hex of 32 bytes which aren't a valid ed25519 public key.
+pub(crate) fn invalid_key() -> String {
+    let bytes = (0..=u8::MAX)
+        .map(|first| {
+            let mut bytes = [0; 32];
+            bytes[0] = first;
+            bytes
+        })
+        .find(|bytes| VerifyingKey::from_bytes(bytes).is_err())
+        .expect("some encodings aren't points");
+    hex::encode(bytes)
+}
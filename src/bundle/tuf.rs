@@ -0,0 +1,603 @@
+//! [TUF] repository metadata for a directory of published bundles, so
+//! robots updating from it are protected against rollback to older
+//! bundles and against being served stale metadata indefinitely.
+//!
+//! [`TufRepository::update`] writes `root.json`, `targets.json`,
+//! `snapshot.json` and `timestamp.json` to a metadata directory, listing
+//! every file in the bundle directory as a target. Each role is signed by
+//! its own ed25519 key. A role's metadata is re-signed with a higher
+//! version when its contents change, or once less than half of its
+//! lifetime is left, so running `update` regularly (at least daily, with
+//! the default lifetimes) keeps the repository fresh. The timestamp is
+//! re-signed on every update. [`verify`] checks a role's metadata is
+//! signed by its key.
+//!
+//! Consistent snapshots and delegations aren't used, and root keys can't
+//! yet be rotated: the first `root.json` written is kept.
+//!
+//! [TUF]: https://theupdateframework.github.io/specification/latest/
+
+use super::sbom::timestamp;
+use super::signing::{encode_key, SigningKey, VerifyingKey};
+use super::{BundleError, Result};
+use ed25519_dalek::{Signature, Signer};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SPEC_VERSION: &str = "1.0.31";
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The top-level TUF roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    Root,
+    Targets,
+    Snapshot,
+    Timestamp,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Root, Role::Targets, Role::Snapshot, Role::Timestamp];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Root => "root",
+            Role::Targets => "targets",
+            Role::Snapshot => "snapshot",
+            Role::Timestamp => "timestamp",
+        }
+    }
+
+    /// How long the role's metadata is valid for, unless set otherwise.
+    pub fn default_lifetime(self) -> Duration {
+        Duration::from_secs(match self {
+            Role::Root => 365 * DAY,
+            Role::Targets => 90 * DAY,
+            Role::Snapshot => 7 * DAY,
+            Role::Timestamp => DAY,
+        })
+    }
+
+    fn file_name(self) -> String {
+        format!("{}.json", self.name())
+    }
+}
+
+/// The signing keys for each role.
+#[derive(Debug, Clone)]
+pub struct TufKeys {
+    pub root: SigningKey,
+    pub targets: SigningKey,
+    pub snapshot: SigningKey,
+    pub timestamp: SigningKey,
+}
+
+impl TufKeys {
+    fn get(&self, role: Role) -> &SigningKey {
+        match role {
+            Role::Root => &self.root,
+            Role::Targets => &self.targets,
+            Role::Snapshot => &self.snapshot,
+            Role::Timestamp => &self.timestamp,
+        }
+    }
+}
+
+/// Maintains the TUF metadata for a directory of bundles.
+#[derive(Debug, Clone)]
+pub struct TufRepository {
+    targets: PathBuf,
+    metadata: PathBuf,
+    keys: TufKeys,
+    lifetimes: BTreeMap<Role, Duration>,
+}
+
+impl TufRepository {
+    /// A repository publishing the files in `targets`, with its metadata
+    /// kept in `metadata`.
+    pub fn new<T: AsRef<Path>, M: AsRef<Path>>(targets: T, metadata: M, keys: TufKeys) -> Self {
+        TufRepository {
+            targets: targets.as_ref().to_path_buf(),
+            metadata: metadata.as_ref().to_path_buf(),
+            keys,
+            lifetimes: BTreeMap::new(),
+        }
+    }
+
+    /// Set how long a role's metadata is valid for.
+    pub fn lifetime(&mut self, role: Role, lifetime: Duration) -> &mut Self {
+        self.lifetimes.insert(role, lifetime);
+        self
+    }
+
+    /// Bring the metadata up to date with the bundle directory, returning
+    /// the roles whose metadata was re-signed.
+    pub fn update(&self) -> Result<Vec<Role>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        fs::create_dir_all(&self.metadata)?;
+        let mut updated = Vec::new();
+
+        let root = self.root()?;
+        if let Some(version) = self.refresh(Role::Root, &root, now)? {
+            // Clients walk the chain of root versions, so each is kept.
+            fs::copy(
+                self.metadata.join(Role::Root.file_name()),
+                self.metadata
+                    .join(format!("{}.{}", version, Role::Root.file_name())),
+            )?;
+            updated.push(Role::Root);
+        }
+
+        let targets = self.targets()?;
+        if self.refresh(Role::Targets, &targets, now)?.is_some() {
+            updated.push(Role::Targets);
+        }
+        let targets_version = self.read(Role::Targets)?.map_or(1, |(version, _)| version);
+
+        let snapshot = json!({
+            "meta": {
+                "targets.json": { "version": targets_version },
+            },
+        });
+        if self.refresh(Role::Snapshot, &snapshot, now)?.is_some() {
+            updated.push(Role::Snapshot);
+        }
+        let snapshot_path = self.metadata.join(Role::Snapshot.file_name());
+        let snapshot_bytes = fs::read(&snapshot_path)?;
+        let snapshot_version = self.read(Role::Snapshot)?.map_or(1, |(version, _)| version);
+
+        let timestamp = json!({
+            "meta": {
+                "snapshot.json": {
+                    "version": snapshot_version,
+                    "length": snapshot_bytes.len(),
+                    "hashes": { "sha256": hex::encode(Sha256::digest(&snapshot_bytes)) },
+                },
+            },
+        });
+        let version = self
+            .read(Role::Timestamp)?
+            .map_or(0, |(version, _)| version)
+            + 1;
+        self.write(Role::Timestamp, version, &timestamp, now)?;
+        updated.push(Role::Timestamp);
+        Ok(updated)
+    }
+
+    /// The role-specific part of the root metadata.
+    fn root(&self) -> Result<Value> {
+        let mut keys = Map::new();
+        let mut roles = Map::new();
+        for role in Role::ALL {
+            let (id, key) = public_key(self.keys.get(role));
+            keys.insert(id.clone(), key);
+            roles.insert(
+                role.name().to_string(),
+                json!({ "keyids": [id], "threshold": 1 }),
+            );
+        }
+        let root = json!({
+            "consistent_snapshot": false,
+            "keys": keys,
+            "roles": roles,
+        });
+        if let Some((_, existing)) = self.read(Role::Root)? {
+            if without_header(&existing) != root {
+                return Err(BundleError::Tuf(
+                    "keys differ from the existing root, and rotating them isn't supported"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(root)
+    }
+
+    /// The role-specific part of the targets metadata.
+    fn targets(&self) -> Result<Value> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.targets)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| BundleError::InvalidPath(entry.path()))?;
+            names.push(name);
+        }
+        names.sort();
+        let mut targets = Map::new();
+        for name in names {
+            let (length, sha256) =
+                super::manifest::hash_reader(fs::File::open(self.targets.join(&name))?)?;
+            targets.insert(
+                name,
+                json!({ "length": length, "hashes": { "sha256": sha256 } }),
+            );
+        }
+        Ok(json!({ "targets": targets }))
+    }
+
+    /// Re-sign a role's metadata if its contents have changed or it is
+    /// past half its lifetime, returning its new version if it was.
+    fn refresh(&self, role: Role, contents: &Value, now: u64) -> Result<Option<u64>> {
+        let lifetime = self.lifetime_of(role);
+        let version = match self.read(role)? {
+            Some((version, existing)) => {
+                let expires = existing.get("expires").and_then(Value::as_str);
+                let renew_by = timestamp(now + lifetime / 2);
+                if without_header(&existing) == *contents
+                    && expires.is_some_and(|expires| *expires >= *renew_by)
+                {
+                    return Ok(None);
+                }
+                version + 1
+            }
+            None => 1,
+        };
+        self.write(role, version, contents, now)?;
+        Ok(Some(version))
+    }
+
+    fn lifetime_of(&self, role: Role) -> u64 {
+        self.lifetimes
+            .get(&role)
+            .copied()
+            .unwrap_or_else(|| role.default_lifetime())
+            .as_secs()
+    }
+
+    /// The version and signed part of a role's current metadata.
+    fn read(&self, role: Role) -> Result<Option<(u64, Map<String, Value>)>> {
+        let path = self.metadata.join(role.file_name());
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let malformed = || BundleError::Tuf(format!("malformed {}", path.display()));
+        let metadata: Value = serde_json::from_slice(&data).map_err(|_| malformed())?;
+        let signed = metadata
+            .get("signed")
+            .and_then(Value::as_object)
+            .ok_or_else(malformed)?;
+        if signed.get("_type").and_then(Value::as_str) != Some(role.name()) {
+            return Err(malformed());
+        }
+        let version = signed
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(malformed)?;
+        Ok(Some((version, signed.clone())))
+    }
+
+    fn write(&self, role: Role, version: u64, contents: &Value, now: u64) -> Result<()> {
+        let mut signed = Map::new();
+        signed.insert("_type".to_string(), role.name().into());
+        signed.insert("spec_version".to_string(), SPEC_VERSION.into());
+        signed.insert("version".to_string(), version.into());
+        signed.insert(
+            "expires".to_string(),
+            timestamp(now + self.lifetime_of(role)).into(),
+        );
+        if let Value::Object(contents) = contents {
+            signed.extend(contents.clone());
+        }
+        let signed = Value::Object(signed);
+
+        let key = self.keys.get(role);
+        let signature = key.sign(&canonical(&signed));
+        let metadata = json!({
+            "signatures": [{
+                "keyid": public_key(key).0,
+                "sig": hex::encode(signature.to_bytes()),
+            }],
+            "signed": signed,
+        });
+
+        // Written then renamed, so clients never see half a file.
+        let path = self.metadata.join(role.file_name());
+        let temp = self.metadata.join(format!(".{}.tmp", role.file_name()));
+        let mut data = serde_json::to_vec_pretty(&metadata).expect("metadata serializes");
+        data.push(b'\n');
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+}
+
+/// A key's ID and its TUF representation.
+fn public_key(key: &SigningKey) -> (String, Value) {
+    let key = key.verifying_key();
+    (key_id(&key), key_value(&key))
+}
+
+fn key_value(key: &VerifyingKey) -> Value {
+    json!({
+        "keytype": "ed25519",
+        "scheme": "ed25519",
+        "keyval": { "public": encode_key(key) },
+    })
+}
+
+/// The SHA-256 of a key's canonical TUF representation, which TUF
+/// identifies it by.
+fn key_id(key: &VerifyingKey) -> String {
+    hex::encode(Sha256::digest(canonical(&key_value(key))))
+}
+
+/// The signed part of some metadata, less the fields every role has.
+fn without_header(signed: &Map<String, Value>) -> Value {
+    let mut contents = signed.clone();
+    for field in ["_type", "spec_version", "version", "expires"] {
+        contents.remove(field);
+    }
+    Value::Object(contents)
+}
+
+/// Check the metadata for `role` in `data` is signed by one of `trusted`,
+/// returning its signed part.
+///
+/// Only the signature is checked, not the version or expiry, which are
+/// for clients to compare against what they have seen before.
+pub fn verify(data: &[u8], role: Role, trusted: &[VerifyingKey]) -> Result<Map<String, Value>> {
+    let malformed = || BundleError::Tuf(format!("malformed {}", role.file_name()));
+    let metadata: Value = serde_json::from_slice(data).map_err(|_| malformed())?;
+    let signed = metadata
+        .get("signed")
+        .and_then(Value::as_object)
+        .ok_or_else(malformed)?;
+    if signed.get("_type").and_then(Value::as_str) != Some(role.name()) {
+        return Err(malformed());
+    }
+    let signatures = metadata
+        .get("signatures")
+        .and_then(Value::as_array)
+        .ok_or_else(malformed)?;
+    let message = canonical(&metadata["signed"]);
+
+    let mut untrusted = None;
+    for signature in signatures {
+        let keyid = signature.get("keyid").and_then(Value::as_str);
+        let key = trusted
+            .iter()
+            .find(|key| Some(key_id(key).as_str()) == keyid);
+        let key = match (key, keyid) {
+            (Some(key), _) => key,
+            (None, keyid) => {
+                untrusted = keyid.map(str::to_string);
+                continue;
+            }
+        };
+        let sig = signature
+            .get("sig")
+            .and_then(Value::as_str)
+            .and_then(|sig| hex::decode(sig).ok())
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(BundleError::MalformedSignature)?;
+        key.verify_strict(&message, &sig)
+            .map_err(|_| BundleError::BadSignature)?;
+        return Ok(signed.clone());
+    }
+    Err(untrusted.map_or(BundleError::Unsigned, BundleError::UntrustedKey))
+}
+
+/// Canonical JSON, as signed by TUF: the [OLPC] form, with object keys
+/// sorted, no whitespace, and only quotes and backslashes escaped in
+/// strings. It is written out here rather than left to serde_json, whose
+/// maps are only sorted while nothing enables its `preserve_order`.
+///
+/// [OLPC]: http://wiki.laptop.org/go/Canonical_JSON
+fn canonical(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.extend_from_slice(b"null"),
+        Value::Bool(value) => out.extend_from_slice(if *value { b"true" } else { b"false" }),
+        // TUF metadata has no need of floats, which canonical JSON can't
+        // represent.
+        Value::Number(number) => {
+            assert!(!number.is_f64(), "canonical JSON has no floats");
+            out.extend_from_slice(number.to_string().as_bytes());
+        }
+        Value::String(string) => write_canonical_string(string, out),
+        Value::Array(array) => {
+            out.push(b'[');
+            for (index, value) in array.iter().enumerate() {
+                if index > 0 {
+                    out.push(b',');
+                }
+                write_canonical(value, out);
+            }
+            out.push(b']');
+        }
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+            out.push(b'{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push(b',');
+                }
+                write_canonical_string(key, out);
+                out.push(b':');
+                write_canonical(value, out);
+            }
+            out.push(b'}');
+        }
+    }
+}
+
+fn write_canonical_string(string: &str, out: &mut Vec<u8>) {
+    out.push(b'"');
+    for byte in string.bytes() {
+        if let b'"' | b'\\' = byte {
+            out.push(b'\\');
+        }
+        out.push(byte);
+    }
+    out.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::testing::TempDir;
+
+    fn keys() -> TufKeys {
+        TufKeys {
+            root: SigningKey::from_bytes(&[1; 32]),
+            targets: SigningKey::from_bytes(&[2; 32]),
+            snapshot: SigningKey::from_bytes(&[3; 32]),
+            timestamp: SigningKey::from_bytes(&[4; 32]),
+        }
+    }
+
+    /// A repository publishing `dir/bundles`, which holds one bundle.
+    fn repository(dir: &TempDir) -> TufRepository {
+        let targets = dir.join("bundles");
+        fs::create_dir_all(&targets).unwrap();
+        fs::write(targets.join("bundle.tar.gz"), "bundle").unwrap();
+        TufRepository::new(targets, dir.join("metadata"), keys())
+    }
+
+    fn signed(dir: &TempDir, role: Role) -> Map<String, Value> {
+        let data = fs::read(dir.join("metadata").join(role.file_name())).unwrap();
+        verify(&data, role, &[keys().get(role).verifying_key()]).unwrap()
+    }
+
+    fn version(dir: &TempDir, role: Role) -> u64 {
+        signed(dir, role)["version"].as_u64().unwrap()
+    }
+
+    #[test]
+    fn canonical_form() {
+        let value = json!({
+            "b": [1, true, null],
+            "a": { "z": "quote \" and \\ kept\n", "é": -2 },
+        });
+        assert_eq!(
+            canonical(&value),
+            "{\"a\":{\"z\":\"quote \\\" and \\\\ kept\n\",\"é\":-2},\"b\":[1,true,null]}"
+                .as_bytes()
+        );
+    }
+
+    #[test]
+    fn roles_are_signed_by_their_keys() {
+        let dir = TempDir::new();
+        repository(&dir).update().unwrap();
+        for role in Role::ALL {
+            let data = fs::read(dir.join("metadata").join(role.file_name())).unwrap();
+            verify(&data, role, &[keys().get(role).verifying_key()]).unwrap();
+
+            let other = if role == Role::Root {
+                Role::Targets
+            } else {
+                Role::Root
+            };
+            assert!(matches!(
+                verify(&data, role, &[keys().get(other).verifying_key()]),
+                Err(BundleError::UntrustedKey(_))
+            ));
+        }
+
+        // The root lists each role's key by its ID.
+        let root = signed(&dir, Role::Root);
+        for role in Role::ALL {
+            let id = key_id(&keys().get(role).verifying_key());
+            assert_eq!(root["roles"][role.name()]["keyids"], json!([id]));
+            assert_eq!(
+                root["keys"][&id]["keyval"]["public"],
+                encode_key(&keys().get(role).verifying_key())
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_metadata() {
+        let dir = TempDir::new();
+        repository(&dir).update().unwrap();
+        let path = dir.join("metadata").join(Role::Targets.file_name());
+        let mut metadata: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        metadata["signed"]["version"] = 5.into();
+        let data = serde_json::to_vec(&metadata).unwrap();
+        assert!(matches!(
+            verify(&data, Role::Targets, &[keys().targets.verifying_key()]),
+            Err(BundleError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn versions_go_up_when_contents_change() {
+        let dir = TempDir::new();
+        let repository = repository(&dir);
+        assert_eq!(repository.update().unwrap(), Role::ALL);
+        for role in Role::ALL {
+            assert_eq!(version(&dir, role), 1);
+        }
+
+        // Only the timestamp is re-signed when nothing has changed.
+        assert_eq!(repository.update().unwrap(), [Role::Timestamp]);
+        assert_eq!(version(&dir, Role::Targets), 1);
+        assert_eq!(version(&dir, Role::Timestamp), 2);
+
+        fs::write(dir.join("bundles").join("new.tar.gz"), "new").unwrap();
+        assert_eq!(
+            repository.update().unwrap(),
+            [Role::Targets, Role::Snapshot, Role::Timestamp]
+        );
+        assert_eq!(version(&dir, Role::Root), 1);
+        assert_eq!(version(&dir, Role::Targets), 2);
+        assert_eq!(version(&dir, Role::Snapshot), 2);
+        assert_eq!(version(&dir, Role::Timestamp), 3);
+        assert!(signed(&dir, Role::Targets)["targets"]
+            .get("new.tar.gz")
+            .is_some());
+        assert_eq!(
+            signed(&dir, Role::Snapshot)["meta"]["targets.json"]["version"],
+            2
+        );
+    }
+
+    #[test]
+    fn renews_metadata_near_expiry() {
+        let dir = TempDir::new();
+        let repository = repository(&dir);
+        repository.update().unwrap();
+
+        // Make the targets metadata look like it was signed long ago.
+        let path = dir.join("metadata").join(Role::Targets.file_name());
+        let mut metadata: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        metadata["signed"]["expires"] = timestamp(0).into();
+        fs::write(&path, serde_json::to_vec(&metadata).unwrap()).unwrap();
+
+        assert_eq!(
+            repository.update().unwrap(),
+            [Role::Targets, Role::Snapshot, Role::Timestamp]
+        );
+        assert_eq!(version(&dir, Role::Targets), 2);
+        let expires = signed(&dir, Role::Targets)["expires"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(*expires > *timestamp(now + Role::Targets.default_lifetime().as_secs() / 2));
+    }
+}
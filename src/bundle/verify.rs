@@ -0,0 +1,413 @@
+use super::reader::{parse_manifest, Head};
+use super::signing::{encode_key, ManifestSignature, VerifyingKey};
+use super::{
+    Bundle, BundleError, Manifest, ManifestEntry, Payload, Result, MANIFEST_PATH,
+    OPENPGP_SIGNATURE_PATH, SIGNATURE_PATH,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Something wrong with a bundle's contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    MissingEntry(String),
+    UnexpectedEntry(String),
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    HashMismatch(String),
+    /// A component's hash doesn't match the manifest entries for its
+    /// files.
+    ComponentMismatch(Payload),
+    MalformedManifest,
+    BadSignature,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MissingEntry(path) => write!(f, "{} is missing", path),
+            Problem::UnexpectedEntry(path) => write!(f, "{} is not in the manifest", path),
+            Problem::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is {} bytes, expected {} bytes",
+                path, actual, expected
+            ),
+            Problem::HashMismatch(path) => write!(f, "{} does not match its hash", path),
+            Problem::ComponentMismatch(payload) => write!(
+                f,
+                "{} component does not match its files",
+                payload.directory()
+            ),
+            Problem::MalformedManifest => write!(f, "manifest cannot be parsed"),
+            Problem::BadSignature => write!(f, "signature does not match the manifest"),
+        }
+    }
+}
+
+/// The outcome of verifying a bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The contents match the manifest, which is signed by `key`.
+    Ok { key: String },
+    /// The contents or signature have been tampered with or damaged.
+    Corrupt(Vec<Problem>),
+    /// The contents match the manifest, but it is not signed.
+    Unsigned,
+    /// The contents match the manifest, but it is signed by an unknown key.
+    UntrustedKey(String),
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Verification::Ok { .. })
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verification::Ok { key } => write!(f, "ok, signed by {}", key),
+            Verification::Corrupt(problems) => {
+                write!(f, "corrupt:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            Verification::Unsigned => write!(f, "unsigned"),
+            Verification::UntrustedKey(key) => write!(f, "signed by untrusted key {}", key),
+        }
+    }
+}
+
+impl Bundle {
+    /// Check every entry against the manifest, and the manifest's ed25519
+    /// signature against `trusted`.
+    ///
+    /// Only failures to read the archive at all are returned as errors.
+    pub fn verify(&self, trusted: &[VerifyingKey]) -> Result<Verification> {
+        self.verify_with(|head| {
+            let signature = head.signature.as_ref().ok_or(BundleError::Unsigned)?;
+            let signature = ManifestSignature::from_bytes(signature)?;
+            signature.verify(&head.manifest, trusted)?;
+            Ok(encode_key(signature.key()))
+        })
+    }
+
+    /// As [`Bundle::verify`], but checking the OpenPGP signature instead.
+    #[cfg(feature = "openpgp")]
+    pub fn verify_openpgp(&self, trusted: &[super::openpgp::Cert]) -> Result<Verification> {
+        self.verify_with(|head| {
+            let signature = head
+                .openpgp_signature
+                .as_ref()
+                .ok_or(BundleError::Unsigned)?;
+            super::openpgp::verify_detached(signature, &head.manifest, trusted)
+        })
+    }
+
+    fn verify_with<F>(&self, check_signature: F) -> Result<Verification>
+    where
+        F: FnOnce(&Head) -> Result<String>,
+    {
+        let head = match self.head() {
+            Ok(head) => head,
+            Err(BundleError::MissingEntry(path)) => {
+                return Ok(Verification::Corrupt(vec![Problem::MissingEntry(
+                    path.to_string(),
+                )]));
+            }
+            Err(e) => return Err(e),
+        };
+        let manifest = match parse_manifest(&head.manifest) {
+            Ok(manifest) => manifest,
+            Err(e @ (BundleError::NewerFormat(_) | BundleError::UnsupportedFormat(_))) => {
+                return Err(e)
+            }
+            Err(_) => return Ok(Verification::Corrupt(vec![Problem::MalformedManifest])),
+        };
+
+        let mut problems = self.check_contents(&manifest)?;
+        problems.extend(check_components(&manifest));
+        let signature = check_signature(&head);
+        if let Err(BundleError::BadSignature) | Err(BundleError::MalformedSignature) = signature {
+            problems.push(Problem::BadSignature);
+        }
+        if !problems.is_empty() {
+            return Ok(Verification::Corrupt(problems));
+        }
+
+        match signature {
+            Ok(key) => Ok(Verification::Ok { key }),
+            Err(BundleError::Unsigned) => Ok(Verification::Unsigned),
+            Err(BundleError::UntrustedKey(key)) => Ok(Verification::UntrustedKey(key)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hash every entry, comparing against the manifest.
+    ///
+    /// Decompression is inherently sequential, so this thread reads the
+    /// archive and hands each file's data to a pool of hashing threads.
+    fn check_contents(&self, manifest: &Manifest) -> Result<Vec<Problem>> {
+        let mut expected: HashMap<&str, _> = manifest
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+        let mut problems = Vec::new();
+        let mut archive = self.archive()?;
+
+        let (jobs, queue) = mpsc::channel();
+        let queue = Mutex::new(queue);
+        let mut hashed = thread::scope(|scope| {
+            let queue = &queue;
+            let workers: Vec<_> = (0..hashing_threads())
+                .map(|_| scope.spawn(move || hash_jobs(queue)))
+                .collect();
+            let read = feed_jobs(&mut archive, &jobs, &mut expected, &mut problems);
+            drop(jobs);
+            let mut hashed = Vec::new();
+            for worker in workers {
+                hashed.extend(worker.join().expect("hashing thread panicked"));
+            }
+            read.map(|_| hashed)
+        })?;
+
+        hashed.sort_by_key(|h| h.index);
+        for Hashed {
+            entry,
+            size,
+            sha256,
+            ..
+        } in hashed
+        {
+            if size != entry.size {
+                problems.push(Problem::SizeMismatch {
+                    path: entry.path.clone(),
+                    expected: entry.size,
+                    actual: size,
+                });
+            } else if sha256 != entry.sha256 {
+                problems.push(Problem::HashMismatch(entry.path.clone()));
+            }
+        }
+
+        let mut missing: Vec<_> = expected.keys().map(|path| path.to_string()).collect();
+        missing.sort();
+        problems.extend(missing.into_iter().map(Problem::MissingEntry));
+        Ok(problems)
+    }
+}
+
+/// Check each component recorded in the manifest against its files'
+/// entries.
+fn check_components(manifest: &Manifest) -> Vec<Problem> {
+    manifest
+        .components
+        .iter()
+        .filter(|component| component.sha256 != manifest.component_digest(component.payload))
+        .map(|component| Problem::ComponentMismatch(component.payload))
+        .collect()
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks may be queued for a hashing thread before the reader
+/// waits for it to catch up.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+struct Job<'m> {
+    index: usize,
+    entry: &'m ManifestEntry,
+    chunks: Receiver<Vec<u8>>,
+}
+
+struct Hashed<'m> {
+    index: usize,
+    entry: &'m ManifestEntry,
+    size: u64,
+    sha256: String,
+}
+
+fn hashing_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Read every payload entry, queueing a hashing job for each which is in
+/// the manifest and noting those which aren't.
+fn feed_jobs<'m, R: Read>(
+    archive: &mut tar::Archive<R>,
+    jobs: &Sender<Job<'m>>,
+    expected: &mut HashMap<&str, &'m ManifestEntry>,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    for (index, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if let MANIFEST_PATH | SIGNATURE_PATH | OPENPGP_SIGNATURE_PATH = path.as_str() {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            continue;
+        }
+        let manifest_entry = match expected.remove(path.as_str()) {
+            Some(manifest_entry) if kind.is_file() => manifest_entry,
+            _ => {
+                problems.push(Problem::UnexpectedEntry(path));
+                continue;
+            }
+        };
+
+        let (chunks, receiver) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+        jobs.send(Job {
+            index,
+            entry: manifest_entry,
+            chunks: receiver,
+        })
+        .expect("hashing threads outlive the reader");
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let n = entry.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            chunk.truncate(n);
+            chunks
+                .send(chunk)
+                .expect("hashing threads outlive the reader");
+        }
+    }
+    Ok(())
+}
+
+fn hash_jobs<'m>(queue: &Mutex<Receiver<Job<'m>>>) -> Vec<Hashed<'m>> {
+    let mut hashed = Vec::new();
+    loop {
+        let job = queue.lock().expect("job queue poisoned").recv();
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return hashed,
+        };
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        for chunk in job.chunks {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+        hashed.push(Hashed {
+            index: job.index,
+            entry: job.entry,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::signing::SigningKey;
+    use crate::bundle::testing::{builder, invalid_key, rewrite_tarball, TempDir};
+
+    const FILES: &[(&str, &str)] = &[("main.py", "print(1)\n")];
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Build a bundle, signed by `signer` if given.
+    fn bundle(dir: &TempDir, signer: Option<&SigningKey>) -> Bundle {
+        let mut builder = builder(dir.path(), "[kit]\nname = \"kit\"\n", FILES);
+        if let Some(signer) = signer {
+            builder.sign(signer.clone());
+        }
+        let path = dir.join("bundle.tar.gz");
+        builder.build(&path).unwrap();
+        Bundle::open(&path).unwrap()
+    }
+
+    /// A copy of `bundle` with `change` made to it.
+    fn tampered<F: FnMut(&str, &mut Vec<u8>)>(dir: &TempDir, bundle: &Bundle, change: F) -> Bundle {
+        let path = dir.join("tampered.tar.gz");
+        rewrite_tarball(bundle.path(), &path, change);
+        Bundle::open(&path).unwrap()
+    }
+
+    #[test]
+    fn ok() {
+        let dir = TempDir::new();
+        let bundle = bundle(&dir, Some(&key(1)));
+        assert_eq!(
+            bundle.verify(&[key(1).verifying_key()]).unwrap(),
+            Verification::Ok {
+                key: encode_key(&key(1).verifying_key())
+            }
+        );
+    }
+
+    #[test]
+    fn unsigned() {
+        let dir = TempDir::new();
+        let bundle = bundle(&dir, None);
+        assert_eq!(
+            bundle.verify(&[key(1).verifying_key()]).unwrap(),
+            Verification::Unsigned
+        );
+    }
+
+    #[test]
+    fn untrusted() {
+        let dir = TempDir::new();
+        let bundle = bundle(&dir, Some(&key(1)));
+        assert_eq!(
+            bundle.verify(&[key(2).verifying_key()]).unwrap(),
+            Verification::UntrustedKey(encode_key(&key(1).verifying_key()))
+        );
+    }
+
+    #[test]
+    fn corrupt_contents() {
+        let dir = TempDir::new();
+        let bundle = bundle(&dir, Some(&key(1)));
+        let bundle = tampered(&dir, &bundle, |path, data| {
+            if path == "usercode/main.py" {
+                *data = b"print(2)\n".to_vec();
+            }
+        });
+        assert_eq!(
+            bundle.verify(&[key(1).verifying_key()]).unwrap(),
+            Verification::Corrupt(vec![Problem::HashMismatch("usercode/main.py".to_string())])
+        );
+    }
+
+    #[test]
+    fn corrupt_signature() {
+        let dir = TempDir::new();
+        let bundle = bundle(&dir, Some(&key(1)));
+        let bundle = tampered(&dir, &bundle, |path, data| {
+            if path == SIGNATURE_PATH {
+                let text = String::from_utf8(data.clone()).unwrap();
+                let key = encode_key(&key(1).verifying_key());
+                *data = text.replace(&key, &invalid_key()).into_bytes();
+            }
+        });
+        assert_eq!(
+            bundle.verify(&[key(1).verifying_key()]).unwrap(),
+            Verification::Corrupt(vec![Problem::BadSignature])
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Kit versions, as in the `kit.version` field of bundle TOML.
+//!
+//! Versions are `MAJOR.MINOR.PATCH`, optionally after an `EPOCH:` for
+//! when numbering has to start again, and optionally followed by
+//! `+BUILD` information, such as the commit a development build came
+//! from.
+
+use super::BundleError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Which part of a version to increase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bump {
+    Patch,
+    /// Resets the patch number.
+    Minor,
+    /// Resets the minor and patch numbers.
+    Major,
+    /// Restarts numbering at 1.0.0.
+    Epoch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KitVersion {
+    pub epoch: u64,
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub build: Option<String>,
+}
+
+impl KitVersion {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        KitVersion {
+            epoch: 0,
+            major,
+            minor,
+            patch,
+            build: None,
+        }
+    }
+
+    /// The next version, without any build information. Fails if the
+    /// part being increased is already as large as it can be.
+    pub fn bump(&self, part: Bump) -> Result<Self, BundleError> {
+        let (epoch, major, minor, patch) = (self.epoch, self.major, self.minor, self.patch);
+        let next = |number: u64| {
+            number
+                .checked_add(1)
+                .ok_or_else(|| BundleError::InvalidVersion(self.to_string()))
+        };
+        let (epoch, major, minor, patch) = match part {
+            Bump::Patch => (epoch, major, minor, next(patch)?),
+            Bump::Minor => (epoch, major, next(minor)?, 0),
+            Bump::Major => (epoch, next(major)?, 0, 0),
+            Bump::Epoch => (next(epoch)?, 1, 0, 0),
+        };
+        Ok(KitVersion {
+            epoch,
+            ..KitVersion::new(major, minor, patch)
+        })
+    }
+
+    /// The same version, with `build` information, which is dot-separated
+    /// identifiers of ASCII letters, digits and hyphens.
+    pub fn with_build<B: Into<String>>(&self, build: B) -> Result<Self, BundleError> {
+        let build = build.into();
+        if !valid_build(&build) {
+            return Err(BundleError::InvalidVersion(format!("{}+{}", self, build)));
+        }
+        Ok(KitVersion {
+            build: Some(build),
+            ..self.clone()
+        })
+    }
+}
+
+fn valid_build(build: &str) -> bool {
+    build.split('.').all(|identifier| {
+        !identifier.is_empty()
+            && identifier
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+impl FromStr for KitVersion {
+    type Err = BundleError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let invalid = || BundleError::InvalidVersion(version.to_string());
+        let number = |part: &str| {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            part.parse::<u64>().map_err(|_| invalid())
+        };
+        let (version_part, build) = match version.split_once('+') {
+            Some((version, build)) if valid_build(build) => (version, Some(build.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (version, None),
+        };
+        let (epoch, release) = match version_part.split_once(':') {
+            Some((epoch, release)) => (number(epoch)?, release),
+            None => (0, version_part),
+        };
+        let parts = release
+            .split('.')
+            .map(number)
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts[..] {
+            [major, minor, patch] => Ok(KitVersion {
+                epoch,
+                build,
+                ..KitVersion::new(major, minor, patch)
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for KitVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(build) = &self.build {
+            write!(f, "+{}", build)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> KitVersion {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses() {
+        assert_eq!(version("1.2.3"), KitVersion::new(1, 2, 3));
+        assert_eq!(
+            version("2:1.2.3+git.1a2b3c4.dirty"),
+            KitVersion {
+                epoch: 2,
+                build: Some("git.1a2b3c4.dirty".to_string()),
+                ..KitVersion::new(1, 2, 3)
+            }
+        );
+        for invalid in [
+            "",
+            "1.2",
+            "1.2.3.4",
+            "1.2.x",
+            "-1.2.3",
+            "1.2.+3",
+            ":1.2.3",
+            "1:2:1.2.3",
+            "1.2.3+",
+            "1.2.3+a..b",
+            "1.2.3+a_b",
+            "1.2.18446744073709551616",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<KitVersion>(),
+                    Err(BundleError::InvalidVersion(_))
+                ),
+                "{:?} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn displays() {
+        for text in ["1.2.3", "2:1.2.3", "1.2.3+git.1a2b3c4", "1:0.0.0+dev"] {
+            assert_eq!(version(text).to_string(), text);
+        }
+        assert_eq!(version("0:01.2.3").to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn bumps() {
+        let current = version("1:1.2.3+git.1a2b3c4");
+        let bumped = |part| current.bump(part).unwrap().to_string();
+        assert_eq!(bumped(Bump::Patch), "1:1.2.4");
+        assert_eq!(bumped(Bump::Minor), "1:1.3.0");
+        assert_eq!(bumped(Bump::Major), "1:2.0.0");
+        assert_eq!(bumped(Bump::Epoch), "2:1.0.0");
+    }
+
+    #[test]
+    fn bumping_past_the_largest_number_fails() {
+        let max = u64::MAX;
+        for (text, part) in [
+            (format!("1.2.{}", max), Bump::Patch),
+            (format!("1.{}.3", max), Bump::Minor),
+            (format!("{}.2.3", max), Bump::Major),
+            (format!("{}:1.2.3", max), Bump::Epoch),
+        ] {
+            assert!(matches!(
+                version(&text).bump(part),
+                Err(BundleError::InvalidVersion(_))
+            ));
+        }
+        // Numbers which are reset don't matter.
+        assert_eq!(
+            version(&format!("1.2.{}", max)).bump(Bump::Minor).unwrap(),
+            KitVersion::new(1, 3, 0)
+        );
+    }
+
+    #[test]
+    fn adds_build_information() {
+        let current = KitVersion::new(1, 2, 3);
+        assert_eq!(
+            current.with_build("git.1a2b3c4").unwrap().to_string(),
+            "1.2.3+git.1a2b3c4"
+        );
+        assert!(current.with_build("git 1a2b3c4").is_err());
+    }
+}
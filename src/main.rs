@@ -1,3 +1,1071 @@
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, Subcommand, ValueEnum};
+use robot_bundler::bundle::edit::MetadataFile;
+use robot_bundler::bundle::encryption;
+use robot_bundler::bundle::signing::{decode_signing_key, decode_verifying_key, VerifyingKey};
+use robot_bundler::bundle::version::{Bump, KitVersion};
+use robot_bundler::bundle::{
+    BuildConfig, Bundle, BundleError, BundleInfo, Codec, Compression, Container, Manifest, Problem,
+    Result, Verification, MANIFEST_PATH, METADATA_PATH, SIGNATURE_PATH,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Build, check and look inside robot bundles.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build a bundle from a build configuration.
+    Create {
+        /// The build configuration TOML.
+        config: PathBuf,
+        /// Where to write the bundle. Architecture variants are written
+        /// alongside it, named after it.
+        #[arg(short, long, default_value = "bundle.tar.gz")]
+        output: PathBuf,
+        /// File holding the hex ed25519 key to sign the bundle with.
+        #[arg(long)]
+        key: Option<PathBuf>,
+        #[arg(long, value_enum)]
+        compression: Option<CodecArg>,
+        /// Compression level, which defaults to the codec's usual one.
+        #[arg(long, requires = "compression")]
+        level: Option<u32>,
+    },
+    /// Check a bundle's contents against its manifest, and its signature,
+    /// or just the syntax of a bundle TOML file.
+    Validate {
+        bundle: PathBuf,
+        /// File holding a hex ed25519 public key to trust. If any are
+        /// given, the bundle must be signed by one of them.
+        #[arg(long)]
+        trust: Vec<PathBuf>,
+        /// File holding an age identity to decrypt the bundle with.
+        #[arg(long)]
+        identity: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Show a bundle's metadata and manifest.
+    Inspect {
+        bundle: PathBuf,
+        /// File holding an age identity to decrypt the bundle with.
+        #[arg(long)]
+        identity: Vec<PathBuf>,
+        /// Show the values of metadata fields which look like passwords
+        /// or keys, rather than hiding them.
+        #[arg(long)]
+        show_secrets: bool,
+    },
+    /// Show what differs between two bundles, or bundle TOML files: the
+    /// metadata fields, and for bundles the files. Exits with 1 if they
+    /// differ.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Show the values of fields which look like passwords or keys,
+        /// rather than just whether they changed.
+        #[arg(long)]
+        show_secrets: bool,
+    },
+    /// Print a field of a bundle TOML file, or of a bundle's metadata, as
+    /// TOML. Exits with 1 if it isn't set.
+    Get {
+        /// Dotted path of the field, such as `kit.version`.
+        field: String,
+        file: PathBuf,
+        /// Print strings without quotes or escaping.
+        #[arg(long, conflicts_with = "json")]
+        raw: bool,
+        /// Print the field as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Set a field in bundle TOML files, keeping their comments and
+    /// layout. Nothing is written unless it can be set in every file.
+    Set {
+        /// Dotted path of the field, such as `wifi.psk`.
+        field: String,
+        /// The new value. Fields keep their type, so replacing a string
+        /// needs no quotes; new fields are strings unless the value is
+        /// valid TOML.
+        value: String,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Increase `kit.version` in a bundle TOML file.
+    Bump {
+        #[arg(value_enum)]
+        part: BumpArg,
+        file: PathBuf,
+        /// Add the commit the file is at to the version, with `.dirty` if
+        /// it has uncommitted changes, such as 1.2.4+git.1a2b3c4.
+        #[arg(long)]
+        dev: bool,
+    },
+    /// Write a new bundle TOML, asking for what goes in it.
+    Init {
+        #[arg(default_value = METADATA_PATH)]
+        path: PathBuf,
+        /// Replace the file if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CodecArg {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    /// A JSON object with a `diagnostics` list, for CI and other tools.
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BumpArg {
+    Patch,
+    Minor,
+    Major,
+    Epoch,
+}
+
+impl From<BumpArg> for Bump {
+    fn from(part: BumpArg) -> Self {
+        match part {
+            BumpArg::Patch => Bump::Patch,
+            BumpArg::Minor => Bump::Minor,
+            BumpArg::Major => Bump::Major,
+            BumpArg::Epoch => Bump::Epoch,
+        }
+    }
+}
+
+impl From<CodecArg> for Codec {
+    fn from(codec: CodecArg) -> Self {
+        match codec {
+            CodecArg::Gzip => Codec::Gzip,
+            CodecArg::Zstd => Codec::Zstd,
+            CodecArg::Xz => Codec::Xz,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Create {
+            config,
+            output,
+            key,
+            compression,
+            level,
+        } => create(&config, &output, key.as_deref(), compression, level),
+        Command::Validate {
+            bundle,
+            trust,
+            identity,
+            format,
+        } => validate(&bundle, &trust, &identity, format),
+        Command::Inspect {
+            bundle,
+            identity,
+            show_secrets,
+        } => inspect(&bundle, &identity, show_secrets),
+        Command::Diff {
+            old,
+            new,
+            show_secrets,
+        } => diff(&old, &new, show_secrets),
+        Command::Get {
+            field,
+            file,
+            raw,
+            json,
+        } => get(&field, &file, raw, json),
+        Command::Set {
+            field,
+            value,
+            files,
+        } => set(&field, &value, &files),
+        Command::Bump { part, file, dev } => bump(part.into(), &file, dev),
+        Command::Init { path, force } => init(&path, force),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn create(
+    config: &Path,
+    output: &Path,
+    key: Option<&Path>,
+    codec: Option<CodecArg>,
+    level: Option<u32>,
+) -> Result<ExitCode> {
+    let config = BuildConfig::load(config)?;
+    let key = key
+        .map(|path| decode_signing_key(&fs::read_to_string(path)?))
+        .transpose()?;
+    let compression = codec
+        .map(|codec| {
+            let codec = Codec::from(codec);
+            Compression::new(codec, level.unwrap_or_else(|| codec.default_level()))
+        })
+        .transpose()?;
+
+    let built = config.build_all_with(output, |builder| {
+        if let Some(key) = &key {
+            builder.sign(key.clone());
+        }
+        if let Some(compression) = compression {
+            builder.compression(compression);
+        }
+    })?;
+    for (path, manifest) in built {
+        println!(
+            "{}: {} files, {} bytes",
+            path.display(),
+            manifest.entries.len(),
+            manifest.total_size()
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+/// One thing `validate` found, with where it was found: the dotted path
+/// of a metadata field, such as `kit.version`, or otherwise the path of
+/// an entry in the bundle, or none if it's with the whole file. TOML
+/// problems also have the 1-based line and column in the bundle TOML.
+#[derive(Serialize)]
+struct Diagnostic {
+    severity: Severity,
+    path: Option<String>,
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, path: &str, message: String) -> Self {
+        Diagnostic {
+            severity,
+            path: Some(path.to_string()),
+            message,
+            line: None,
+            column: None,
+        }
+    }
+
+    /// Set the line and column from an offset into `source`.
+    fn at(mut self, source: &str, span: Option<Range<usize>>) -> Self {
+        if let Some(Range { start, .. }) = span {
+            let before = &source[..start];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            self.line = Some(before.matches('\n').count() + 1);
+            self.column = Some(before[line_start..].chars().count() + 1);
+        }
+        self
+    }
+
+    /// Check `source` is valid TOML, and if it is, that the fields
+    /// `init` asks for are too.
+    fn check_toml(path: &str, source: &str) -> Vec<Self> {
+        let document = match toml_edit::Document::parse(source) {
+            Ok(document) => document,
+            Err(error) => {
+                let diagnostic =
+                    Diagnostic::new(Severity::Error, path, error.message().to_string());
+                return vec![diagnostic.at(source, error.span())];
+            }
+        };
+        let mut diagnostics = Vec::new();
+        for &(field, check) in FIELD_CHECKS {
+            let item = match field_item(document.as_table(), field) {
+                Some(item) => item,
+                None => continue,
+            };
+            let problem = match item.as_str() {
+                Some(value) => check(value).err(),
+                None => Some("expected a string".to_string()),
+            };
+            if let Some(problem) = problem {
+                let diagnostic = Diagnostic::new(Severity::Error, field, problem);
+                diagnostics.push(diagnostic.at(source, item.span()));
+            }
+        }
+        diagnostics
+    }
+}
+
+/// Fields of bundle TOML which must be valid if they are set, and how to
+/// check them.
+const FIELD_CHECKS: &[(&str, Check)] = &[
+    ("kit.name", check_name),
+    ("kit.version", check_any_version),
+    ("team.tla", check_tla),
+    ("team.name", check_name),
+    ("wifi.ssid", check_ssid),
+    ("wifi.psk", check_psk),
+];
+
+/// The item at the dotted path `field`, if it is set.
+fn field_item<'a>(table: &'a toml_edit::Table, field: &str) -> Option<&'a toml_edit::Item> {
+    let (parents, last) = field.rsplit_once('.').unwrap_or(("", field));
+    let mut table: &dyn toml_edit::TableLike = table;
+    for key in parents.split('.').filter(|key| !key.is_empty()) {
+        table = table.get(key)?.as_table_like()?;
+    }
+    table.get(last)
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    path: &'a Path,
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signed_by: Option<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn validate(
+    bundle: &Path,
+    trust: &[PathBuf],
+    identity: &[PathBuf],
+    format: Format,
+) -> Result<ExitCode> {
+    let trusted = trust
+        .iter()
+        .map(|path| decode_verifying_key(&fs::read_to_string(path)?))
+        .collect::<Result<Vec<_>>>()?;
+    let mut report = Report {
+        path: bundle,
+        valid: true,
+        signed_by: None,
+        diagnostics: Vec::new(),
+    };
+    if let Err(e) = check(bundle, &trusted, identity, &mut report) {
+        // Tools reading JSON get every failure as a diagnostic.
+        if format == Format::Text {
+            return Err(e);
+        }
+        report.diagnostics.push(Diagnostic {
+            path: None,
+            ..Diagnostic::new(Severity::Error, "", e.to_string())
+        });
+    }
+    report.valid = report
+        .diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.severity == Severity::Warning);
+
+    match format {
+        Format::Text => print_report(&report),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("reports serialize")
+        ),
+    }
+    Ok(if report.valid {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Check a bundle, or a TOML file, adding what's wrong with it to `report`.
+fn check(
+    bundle: &Path,
+    trusted: &[VerifyingKey],
+    identity: &[PathBuf],
+    report: &mut Report,
+) -> Result<()> {
+    if bundle
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        let name = bundle.file_name().unwrap_or_default().to_string_lossy();
+        let source = fs::read_to_string(bundle)?;
+        report
+            .diagnostics
+            .extend(Diagnostic::check_toml(&name, &source));
+    } else {
+        let bundle = open_bundle(bundle, identity)?;
+        let signature_problem = if trusted.is_empty() {
+            Severity::Warning
+        } else {
+            Severity::Error
+        };
+        match bundle.verify(trusted)? {
+            Verification::Ok { key } => report.signed_by = Some(key),
+            Verification::Unsigned => report.diagnostics.push(Diagnostic::new(
+                signature_problem,
+                SIGNATURE_PATH,
+                "bundle is not signed".to_string(),
+            )),
+            Verification::UntrustedKey(key) => report.diagnostics.push(Diagnostic::new(
+                signature_problem,
+                SIGNATURE_PATH,
+                format!("signed by untrusted key {}", key),
+            )),
+            Verification::Corrupt(problems) => {
+                report.diagnostics.extend(problems.iter().map(|problem| {
+                    Diagnostic::new(Severity::Error, problem_path(problem), problem.to_string())
+                }))
+            }
+        }
+        // A missing bundle.toml is one of the problems verifying found.
+        if let Ok(metadata) = bundle.metadata() {
+            report
+                .diagnostics
+                .extend(Diagnostic::check_toml(METADATA_PATH, &metadata));
+        }
+    }
+    Ok(())
+}
+
+/// Open a bundle, to be decrypted with the age identities in the files
+/// at `identity`.
+fn open_bundle(bundle: &Path, identity: &[PathBuf]) -> Result<Bundle> {
+    let identities = identity
+        .iter()
+        .map(|path| encryption::parse_identity(&fs::read_to_string(path)?))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Bundle::open(bundle)?.with_identities(identities))
+}
+
+/// The entry in the bundle a problem is with.
+fn problem_path(problem: &Problem) -> &str {
+    match problem {
+        Problem::MissingEntry(path)
+        | Problem::UnexpectedEntry(path)
+        | Problem::SizeMismatch { path, .. }
+        | Problem::HashMismatch(path) => path,
+        Problem::ComponentMismatch(payload) => payload.directory(),
+        Problem::MalformedManifest => MANIFEST_PATH,
+        Problem::BadSignature => SIGNATURE_PATH,
+    }
+}
+
+fn print_report(report: &Report) {
+    for diagnostic in &report.diagnostics {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let path = diagnostic.path.as_deref().unwrap_or_default();
+        match (diagnostic.line, diagnostic.column) {
+            (Some(line), Some(column)) => println!(
+                "{}: {}:{}:{}: {}",
+                severity, path, line, column, diagnostic.message
+            ),
+            _ => println!("{}: {}: {}", severity, path, diagnostic.message),
+        }
+    }
+    let verdict = if report.valid { "valid" } else { "invalid" };
+    match &report.signed_by {
+        Some(key) => println!("{}: {}, signed by {}", report.path.display(), verdict, key),
+        None => println!("{}: {}", report.path.display(), verdict),
+    }
+}
+
+fn inspect(bundle: &Path, identity: &[PathBuf], show_secrets: bool) -> Result<ExitCode> {
+    let mut info = open_bundle(bundle, identity)?.info()?;
+    if !show_secrets {
+        hide_secrets(&mut info.metadata);
+    }
+    print_info(bundle, &info);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_info(path: &Path, info: &BundleInfo) {
+    let container = match info.container {
+        Container::Tar => format!("tar ({:?})", info.codec).to_lowercase(),
+        Container::Zip => "zip".to_string(),
+    };
+    let yes_no = |value| if value { "yes" } else { "no" };
+    println!("bundle:       {}", path.display());
+    println!("container:    {}", container);
+    println!("encrypted:    {}", yes_no(info.encrypted));
+    println!("signed:       {}", yes_no(info.signed));
+    println!("format:       {}", info.manifest.format);
+    if let Some(architecture) = info.manifest.architecture {
+        println!("architecture: {}", architecture);
+    }
+
+    println!("\nmetadata:");
+    for line in toml::to_string(&info.metadata).unwrap_or_default().lines() {
+        println!("  {}", line);
+    }
+
+    if !info.manifest.components.is_empty() {
+        println!("\ncomponents:");
+        for component in &info.manifest.components {
+            println!(
+                "  {:<10} {:<12} {}",
+                component.payload.directory(),
+                component.version.as_deref().unwrap_or("-"),
+                component.sha256
+            );
+        }
+    }
+
+    println!(
+        "\nfiles ({}, {} bytes):",
+        info.manifest.entries.len(),
+        info.manifest.total_size()
+    );
+    for entry in &info.manifest.entries {
+        println!("  {:>12}  {}", entry.size, entry.path);
+    }
+}
+
+fn diff(old: &Path, new: &Path, show_secrets: bool) -> Result<ExitCode> {
+    let (old_metadata, old_manifest) = load_metadata(old)?;
+    let (new_metadata, new_manifest) = load_metadata(new)?;
+    let old_fields = flatten(&old_metadata);
+    let new_fields = flatten(&new_metadata);
+    let mut different = false;
+
+    let mut keys: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let shown = |value: &toml::Value| {
+            if show_secrets || !is_secret(key) {
+                value.to_string()
+            } else {
+                "(hidden)".to_string()
+            }
+        };
+        match (old_fields.get(key), new_fields.get(key)) {
+            (Some(old), Some(new)) if old == new => continue,
+            (Some(old), Some(new)) => {
+                println!("~ {}: {} -> {}", key, shown(old), shown(new))
+            }
+            (Some(old), None) => println!("- {}: {}", key, shown(old)),
+            (None, Some(new)) => println!("+ {}: {}", key, shown(new)),
+            (None, None) => unreachable!("key came from one side"),
+        }
+        different = true;
+    }
+
+    if let (Some(old), Some(new)) = (&old_manifest, &new_manifest) {
+        for entry in &old.entries {
+            match new.get(&entry.path) {
+                None => println!("- {}", entry.path),
+                Some(changed) if changed != entry => println!("~ {}", entry.path),
+                Some(_) => continue,
+            }
+            different = true;
+        }
+        for entry in &new.entries {
+            if old.get(&entry.path).is_none() {
+                println!("+ {}", entry.path);
+                different = true;
+            }
+        }
+    }
+    Ok(if different {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// The metadata of a bundle TOML file, or the metadata and manifest of a
+/// bundle, going by the file's extension.
+fn load_metadata(path: &Path) -> Result<(toml::Table, Option<Manifest>)> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        let metadata = fs::read_to_string(path)?
+            .parse()
+            .map_err(BundleError::Metadata)?;
+        return Ok((metadata, None));
+    }
+    let info = Bundle::peek(path)?;
+    Ok((info.metadata, Some(info.manifest)))
+}
+
+/// Every value in `table` which isn't itself a table, by its dotted path.
+/// Arrays holding tables are flattened too, with each element's index in
+/// its path, such as `users[0].password`.
+fn flatten(table: &toml::Table) -> BTreeMap<String, toml::Value> {
+    let mut fields = BTreeMap::new();
+    for (key, value) in table {
+        flatten_value(key.clone(), value, &mut fields);
+    }
+    fields
+}
+
+fn flatten_value(path: String, value: &toml::Value, fields: &mut BTreeMap<String, toml::Value>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                flatten_value(format!("{}.{}", path, key), value, fields);
+            }
+        }
+        toml::Value::Array(array) if array.iter().any(toml::Value::is_table) => {
+            for (index, value) in array.iter().enumerate() {
+                flatten_value(format!("{}[{}]", path, index), value, fields);
+            }
+        }
+        value => {
+            fields.insert(path, value.clone());
+        }
+    }
+}
+
+/// Replace the values of fields which look like they hold passwords or
+/// keys, in tables at any depth.
+fn hide_secrets(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        if is_secret(key) {
+            *value = "(hidden)".into();
+            continue;
+        }
+        match value {
+            toml::Value::Table(table) => hide_secrets(table),
+            toml::Value::Array(array) => {
+                for table in array.iter_mut().filter_map(toml::Value::as_table_mut) {
+                    hide_secrets(table);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a field looks like it holds a password or key, by the names
+/// of it and the tables it is in.
+fn is_secret(path: &str) -> bool {
+    const SECRET_WORDS: &[&str] = &["psk", "password", "passphrase", "secret", "token", "key"];
+    path.split('.').any(|key| {
+        let name = key.split('[').next().unwrap_or(key).to_ascii_lowercase();
+        name.split(['_', '-'])
+            .any(|word| SECRET_WORDS.contains(&word))
+    })
+}
+
+fn get(field: &str, path: &Path, raw: bool, json: bool) -> Result<ExitCode> {
+    let (metadata, _) = load_metadata(path)?;
+    let mut keys = field.split('.');
+    let mut value = keys.next().and_then(|key| metadata.get(key));
+    for key in keys {
+        value = value.and_then(|value| value.get(key));
+    }
+    let value = match value {
+        Some(value) => value,
+        None => {
+            eprintln!("error: {} has no {}", path.display(), field);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    if json {
+        println!("{}", to_json(value));
+    } else if let (true, toml::Value::String(string)) = (raw, value) {
+        println!("{}", string);
+    } else if let toml::Value::Table(table) = value {
+        print!("{}", toml::to_string(table).expect("tables serialize"));
+    } else {
+        println!("{}", value);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// TOML as JSON, with dates and times as their TOML strings.
+fn to_json(value: &toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(string) => string.clone().into(),
+        toml::Value::Integer(integer) => (*integer).into(),
+        toml::Value::Float(float) => (*float).into(),
+        toml::Value::Boolean(boolean) => (*boolean).into(),
+        toml::Value::Datetime(datetime) => datetime.to_string().into(),
+        toml::Value::Array(array) => array.iter().map(to_json).collect(),
+        toml::Value::Table(table) => table
+            .iter()
+            .map(|(key, value)| (key.clone(), to_json(value)))
+            .collect(),
+    }
+}
+
+fn set(field: &str, value: &str, files: &[PathBuf]) -> Result<ExitCode> {
+    let mut edited = Vec::new();
+    for path in files {
+        let file = MetadataFile::open(path).and_then(|mut file| {
+            file.set(field, value)?;
+            Ok(file)
+        });
+        match file {
+            Ok(file) => edited.push(file),
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    // Write every file out before replacing any, so a failure leaves them
+    // all as they were.
+    let staged = edited
+        .iter()
+        .map(MetadataFile::stage)
+        .collect::<Result<Vec<_>>>()?;
+    for file in staged {
+        file.commit()?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+const VERSION_FIELD: &str = "kit.version";
+
+fn bump(part: Bump, path: &Path, dev: bool) -> Result<ExitCode> {
+    let mut file = MetadataFile::open(path)?;
+    let current = match file.get(VERSION_FIELD).and_then(|value| value.as_str()) {
+        Some(current) => current.parse::<KitVersion>()?,
+        None => {
+            eprintln!("error: {} has no {}", path.display(), VERSION_FIELD);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    let mut next = current.bump(part)?;
+    if dev {
+        match git_build(path) {
+            Ok(build) => next = next.with_build(build)?,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    file.set(VERSION_FIELD, &next.to_string())?;
+    file.save()?;
+    println!("{} -> {}", current, next);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Build information for the commit the repository holding `path` is at.
+fn git_build(path: &Path) -> std::result::Result<String, String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .map_err(|e| format!("git: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let mut build = format!("git.{}", git(&["rev-parse", "--short", "HEAD"])?);
+    if !git(&["status", "--porcelain"])?.is_empty() {
+        build.push_str(".dirty");
+    }
+    Ok(build)
+}
+
+fn init(path: &Path, force: bool) -> Result<ExitCode> {
+    if path.exists() && !force {
+        eprintln!(
+            "error: {} already exists, use --force to replace it",
+            path.display()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    let mut input = io::stdin().lock();
+    let mut ask = |question: &str, default: Option<&str>, check: Check| {
+        prompt(&mut input, question, default, check)
+    };
+
+    let mut kit = toml::Table::new();
+    kit.insert("name".into(), ask("Kit name", None, check_name)?.into());
+    kit.insert(
+        "version".into(),
+        ask("Kit version", Some("1.0.0"), check_version)?.into(),
+    );
+
+    let mut team = toml::Table::new();
+    team.insert("tla".into(), ask("Team TLA", None, check_tla)?.into());
+    team.insert("name".into(), ask("Team name", None, check_name)?.into());
+
+    let mut metadata = toml::Table::new();
+    metadata.insert("kit".into(), kit.into());
+    metadata.insert("team".into(), team.into());
+
+    let ssid = ask("WiFi network name (blank for none)", Some(""), check_ssid)?;
+    if !ssid.is_empty() {
+        let mut wifi = toml::Table::new();
+        wifi.insert("ssid".into(), ssid.into());
+        wifi.insert("psk".into(), ask("WiFi password", None, check_psk)?.into());
+        metadata.insert("wifi".into(), wifi.into());
+    }
+
+    // Only the owner can read the file, as it may hold the WiFi password.
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = match options.open(path) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!(
+                "error: {} already exists, use --force to replace it",
+                path.display()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+        file => file?,
+    };
+    file.write_all(
+        toml::to_string(&metadata)
+            .expect("metadata serializes")
+            .as_bytes(),
+    )?;
+    eprintln!("Wrote {}", path.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A cleaned-up answer, or why it isn't acceptable.
+type CheckResult = std::result::Result<String, String>;
+
+/// Cleans up and checks an answer, or a field of bundle TOML.
+type Check = fn(&str) -> CheckResult;
+
+/// Ask `question` on stderr until `check` accepts the answer, which is
+/// `default` if nothing is entered.
+fn prompt(
+    input: &mut impl BufRead,
+    question: &str,
+    default: Option<&str>,
+    check: Check,
+) -> io::Result<String> {
+    loop {
+        match default {
+            Some(default) if !default.is_empty() => eprint!("{} [{}]: ", question, default),
+            _ => eprint!("{}: ", question),
+        }
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "input ended before every question was answered",
+            ));
+        }
+        // Only the line ending is removed, as spaces can be part of a
+        // password.
+        let answer = match line.trim_end_matches(['\n', '\r']) {
+            "" => default.unwrap_or_default(),
+            answer => answer,
+        };
+        match check(answer) {
+            Ok(answer) => return Ok(answer),
+            Err(problem) => eprintln!("  {}", problem),
+        }
+    }
+}
+
+fn check_name(name: &str) -> CheckResult {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("a name is needed".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// As [`check_version`], allowing build information, as added by
+/// `bump --dev`.
+fn check_any_version(version: &str) -> CheckResult {
+    match version.parse::<KitVersion>() {
+        Ok(version) => Ok(version.to_string()),
+        Err(_) => Err("versions look like 1.2.3, or 1:1.2.3 with an epoch".to_string()),
+    }
+}
+
+fn check_version(version: &str) -> CheckResult {
+    match version.trim().parse::<KitVersion>() {
+        Ok(version) if version.build.is_none() => Ok(version.to_string()),
+        _ => Err("versions look like 1.2.3, or 1:1.2.3 with an epoch".to_string()),
+    }
+}
+
+/// Team TLAs are three letters, then perhaps a number, such as `ABC2`.
+fn check_tla(tla: &str) -> CheckResult {
+    let tla = tla.trim().to_ascii_uppercase();
+    let bytes = tla.as_bytes();
+    if bytes.len() < 3
+        || !bytes[..3].iter().all(u8::is_ascii_uppercase)
+        || !bytes[3..].iter().all(u8::is_ascii_digit)
+    {
+        return Err("TLAs are three letters, perhaps followed by a number".to_string());
+    }
+    Ok(tla)
+}
+
+fn check_ssid(ssid: &str) -> CheckResult {
+    if ssid.len() > 32 {
+        return Err("WiFi network names are at most 32 bytes".to_string());
+    }
+    Ok(ssid.to_string())
+}
+
+/// WPA passwords are 8 to 63 printable ASCII characters, or 64 hex digits.
+fn check_psk(psk: &str) -> CheckResult {
+    let hex = psk.len() == 64 && psk.bytes().all(|b| b.is_ascii_hexdigit());
+    let passphrase =
+        (8..=63).contains(&psk.len()) && psk.bytes().all(|b| (b' '..=b'~').contains(&b));
+    if !hex && !passphrase {
+        return Err("WiFi passwords are 8 to 63 characters, without accents".to_string());
+    }
+    Ok(psk.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA: &str = r#"
+[kit]
+name = "kit"
+tags = ["a", "b"]
+
+[wifi]
+ssid = "robots"
+psk = "pass word"
+
+[api_key]
+value = "abc"
+
+[[users]]
+name = "admin"
+password = "hunter2"
+
+[[users]]
+name = "guest"
+"#;
+
+    fn metadata() -> toml::Table {
+        METADATA.parse().unwrap()
+    }
+
+    #[test]
+    fn flattens_tables_and_arrays_of_tables() {
+        let fields = flatten(&metadata());
+        let paths: Vec<&str> = fields.keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            [
+                "api_key.value",
+                "kit.name",
+                "kit.tags",
+                "users[0].name",
+                "users[0].password",
+                "users[1].name",
+                "wifi.psk",
+                "wifi.ssid",
+            ]
+        );
+        assert_eq!(fields["users[0].password"].as_str(), Some("hunter2"));
+        assert_eq!(fields["kit.tags"], toml::Value::from(vec!["a", "b"]));
+    }
+
+    #[test]
+    fn finds_secrets_by_any_key() {
+        for secret in [
+            "wifi.psk",
+            "users[0].password",
+            "api_key.value",
+            "signing-key",
+            "Auth.TOKEN",
+        ] {
+            assert!(is_secret(secret), "{} should be secret", secret);
+        }
+        for public in [
+            "wifi.ssid",
+            "users[0].name",
+            "kit.name",
+            "keyboard",
+            "monkey.name",
+        ] {
+            assert!(!is_secret(public), "{} should not be secret", public);
+        }
+    }
+
+    #[test]
+    fn hides_the_same_secrets_as_diff() {
+        let mut hidden = metadata();
+        hide_secrets(&mut hidden);
+        let hidden = flatten(&hidden);
+        for path in flatten(&metadata()).keys() {
+            // Hidden tables leave no fields inside them.
+            let is_hidden = hidden
+                .get(path)
+                .is_none_or(|value| value.as_str() == Some("(hidden)"));
+            assert_eq!(is_hidden, is_secret(path), "{}", path);
+        }
+    }
+
+    fn locations(diagnostics: &[Diagnostic]) -> Vec<(&str, Option<usize>, Option<usize>)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.path.as_deref().unwrap_or_default(), d.line, d.column))
+            .collect()
+    }
+
+    #[test]
+    fn diagnostics_are_at_fields() {
+        let source = "[kit]\nname = \"kit\"\nversion = \"1.2\"\n\n[wifi]\npsk = 12345678\n";
+        let diagnostics = Diagnostic::check_toml(METADATA_PATH, source);
+        assert_eq!(
+            locations(&diagnostics),
+            [
+                ("kit.version", Some(3), Some(11)),
+                ("wifi.psk", Some(6), Some(7))
+            ]
+        );
+        assert!(Diagnostic::check_toml(METADATA_PATH, METADATA).is_empty());
+
+        let diagnostics = Diagnostic::check_toml(METADATA_PATH, "[kit]\nname = \n");
+        assert_eq!(locations(&diagnostics), [(METADATA_PATH, Some(2), Some(8))]);
+    }
 }
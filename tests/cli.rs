@@ -0,0 +1,260 @@
+//! Runs the `robot-bundler` command line against files in a temporary
+//! directory.
+
+use age::secrecy::ExposeSecret;
+use age::x25519::Identity;
+use robot_bundler::bundle::signing::{encode_key, SigningKey};
+use robot_bundler::bundle::{BundleBuilder, Payload};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh directory under the system temp directory, removed on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "robot-bundler-cli-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run the command line in `dir` with `args`, giving it `input`.
+fn run(dir: &TempDir, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_robot-bundler"))
+        .current_dir(&dir.0)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+const METADATA: &str = "[kit]\nname = \"kit\"\nversion = \"1.0.0\"\n\n\
+                        [wifi]\nssid = \"robots\"\npsk = \"wifi-pass\"\n";
+
+/// Write bundle TOML and a build configuration bundling `overlay/` into
+/// `dir`, and build it with `create`.
+fn create(dir: &TempDir) -> Output {
+    fs::write(dir.join("bundle.toml"), METADATA).unwrap();
+    fs::create_dir_all(dir.join("overlay/robot")).unwrap();
+    fs::write(dir.join("overlay/robot/main.py"), "print('hello')\n").unwrap();
+    fs::write(
+        dir.join("build.toml"),
+        "metadata = \"bundle.toml\"\n\n[[assets]]\npath = \"overlay\"\n",
+    )
+    .unwrap();
+    run(dir, &["create", "build.toml"], "")
+}
+
+#[test]
+fn init_keeps_the_password_private() {
+    let dir = TempDir::new();
+    let answers = "kit\n\n abc \nTeam\nrobots\n  pass word  \n";
+    let output = run(&dir, &["init"], answers);
+    assert!(output.status.success(), "{:?}", output);
+
+    let path = dir.join("bundle.toml");
+    let metadata: toml::Table = fs::read_to_string(&path).unwrap().parse().unwrap();
+    assert_eq!(metadata["team"]["tla"].as_str(), Some("ABC"));
+    assert_eq!(metadata["wifi"]["psk"].as_str(), Some("  pass word  "));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // An existing file is only replaced with --force.
+    assert!(!run(&dir, &["init"], answers).status.success());
+    let output = run(&dir, &["init", "--force"], "other\n\nXYZ\nTeam\n\n");
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&run(&dir, &["get", "kit.name", "bundle.toml", "--raw"], "")).contains("other"));
+}
+
+#[test]
+fn set_writes_every_file_or_none() {
+    let dir = TempDir::new();
+    for name in ["a.toml", "b.toml"] {
+        fs::write(dir.join(name), "[wifi]\npsk = \"old-pass\"\n").unwrap();
+    }
+    let output = run(
+        &dir,
+        &["set", "wifi.psk", "new-pass", "a.toml", "b.toml"],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    for name in ["a.toml", "b.toml"] {
+        assert!(fs::read_to_string(dir.join(name))
+            .unwrap()
+            .contains("new-pass"));
+    }
+
+    // b.toml can't be written, so a.toml must be left alone too.
+    fs::write(dir.join(".b.toml.tmp"), "").unwrap();
+    let output = run(
+        &dir,
+        &["set", "wifi.psk", "other-pass", "a.toml", "b.toml"],
+        "",
+    );
+    assert!(!output.status.success());
+    for name in ["a.toml", "b.toml"] {
+        assert!(fs::read_to_string(dir.join(name))
+            .unwrap()
+            .contains("new-pass"));
+    }
+    assert!(!dir.join(".a.toml.tmp").exists());
+}
+
+#[test]
+fn create_validate_and_inspect() {
+    let dir = TempDir::new();
+    let output = create(&dir);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).starts_with("bundle.tar.gz: "));
+
+    let output = run(&dir, &["validate", "bundle.tar.gz"], "");
+    assert!(output.status.success(), "{:?}", output);
+    let report = stdout(&output);
+    assert!(report.contains("warning: manifest.sig: bundle is not signed"));
+    assert!(report.ends_with("bundle.tar.gz: valid\n"));
+
+    let output = run(&dir, &["inspect", "bundle.tar.gz"], "");
+    assert!(output.status.success(), "{:?}", output);
+    let info = stdout(&output);
+    assert!(info.contains("encrypted:    no\n"));
+    assert!(info.contains("ssid = \"robots\""));
+    assert!(info.contains("overlay/robot/main.py"));
+    assert!(!info.contains("wifi-pass"));
+
+    let output = run(&dir, &["inspect", "bundle.tar.gz", "--show-secrets"], "");
+    assert!(stdout(&output).contains("psk = \"wifi-pass\""));
+}
+
+#[test]
+fn validate_checks_who_signed_bundles() {
+    let dir = TempDir::new();
+    let key = SigningKey::from_bytes(&[7; 32]);
+    fs::write(dir.join("key.hex"), hex::encode(key.to_bytes())).unwrap();
+    fs::write(dir.join("trusted.hex"), encode_key(&key.verifying_key())).unwrap();
+    let other = SigningKey::from_bytes(&[8; 32]);
+    fs::write(dir.join("other.hex"), encode_key(&other.verifying_key())).unwrap();
+    assert!(create(&dir).status.success());
+    // Unsigned bundles are invalid once any key is trusted.
+    let output = run(
+        &dir,
+        &["validate", "bundle.tar.gz", "--trust", "trusted.hex"],
+        "",
+    );
+    assert!(!output.status.success());
+
+    let output = run(&dir, &["create", "build.toml", "--key", "key.hex"], "");
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(
+        &dir,
+        &["validate", "bundle.tar.gz", "--trust", "trusted.hex"],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).contains("valid, signed by"));
+    let output = run(
+        &dir,
+        &["validate", "bundle.tar.gz", "--trust", "other.hex"],
+        "",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn encrypted_bundles_need_an_identity() {
+    let dir = TempDir::new();
+    fs::write(dir.join("bundle.toml"), METADATA).unwrap();
+    fs::write(dir.join("main.py"), "print('hello')\n").unwrap();
+    let identity = Identity::generate();
+    fs::write(
+        dir.join("key.txt"),
+        identity.to_string().expose_secret().as_bytes(),
+    )
+    .unwrap();
+    let mut builder = BundleBuilder::new(dir.join("bundle.toml"));
+    builder
+        .add_file(Payload::Usercode, dir.join("main.py"), "main.py")
+        .unwrap();
+    builder
+        .encrypt_to(vec![identity.to_public()])
+        .encrypt_archive()
+        .build(dir.join("bundle.tar.gz"))
+        .unwrap();
+
+    let output = run(&dir, &["inspect", "bundle.tar.gz"], "");
+    assert!(!output.status.success());
+    let output = run(
+        &dir,
+        &["inspect", "bundle.tar.gz", "--identity", "key.txt"],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).contains("encrypted:    yes\n"));
+
+    assert!(!run(&dir, &["validate", "bundle.tar.gz"], "")
+        .status
+        .success());
+    let output = run(
+        &dir,
+        &["validate", "bundle.tar.gz", "--identity", "key.txt"],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[test]
+fn validate_reports_fields_as_json() {
+    let dir = TempDir::new();
+    fs::write(
+        dir.join("bundle.toml"),
+        "[kit]\nname = \"kit\"\nversion = \"one\"\n",
+    )
+    .unwrap();
+    let output = run(&dir, &["validate", "bundle.toml", "--format", "json"], "");
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(report["valid"], false);
+    let diagnostic = &report["diagnostics"][0];
+    assert_eq!(diagnostic["path"], "kit.version");
+    assert_eq!(diagnostic["severity"], "error");
+    assert_eq!(
+        (&diagnostic["line"], &diagnostic["column"]),
+        (&3.into(), &11.into())
+    );
+}